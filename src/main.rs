@@ -1,32 +1,557 @@
 extern crate nom;
+extern crate libc;
+extern crate rustyline;
 
 use nom::IResult;
-use nom::combinator::{opt, recognize, map_res, map};
+use nom::combinator::{opt, recognize, map_res, map, not, value};
 use nom::branch::{alt};
-use nom::sequence::{tuple, pair, delimited, separated_pair};
-use nom::character::complete::{digit1, none_of, one_of};
+use nom::sequence::{tuple, pair, delimited, preceded, separated_pair, terminated};
+use nom::character::complete::{char, digit1, none_of, one_of};
 use nom::bytes::complete::{tag, escaped};
-use nom::multi::{many1, separated_list};
+use nom::multi::{many0, many1, separated_list};
 
-use std::io::{self, Write};
-use std::collections::HashMap;
+use rustyline::{Context, Editor, Helper};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter, MatchingBracketHighlighter};
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+
+use std::io::{self, BufRead, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ffi::CString;
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::str::FromStr;
+use std::convert::TryFrom;
 
 
 #[derive(Debug, Clone)]
 enum Token {
-    Number(i32),
+    Number(i64),
     Boolean(bool),
     Decimal(f32),
+    Char(char),
     String(String),
     Symbol(String),
-    Quotation(Box<Vec<Token>>)
+    Quotation(Vec<Token>),
+    File(Rc<RefCell<JoyFile>>)
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Boolean(b) => write!(f, "{}", b),
+            Token::Decimal(d) => {
+                // always keep a `.` so the literal reads back as a float
+                let s = format!("{:?}", d);
+                match s.find('e') {
+                    Some(i) if !s.contains('.') => write!(f, "{}.0{}", &s[..i], &s[i..]),
+                    _ => write!(f, "{}", s)
+                }
+            },
+            Token::Char(c) => write!(f, "'{}", escape_char(*c)),
+            Token::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '\'' => write!(f, "'")?,
+                        c => write!(f, "{}", escape_char(c))?
+                    }
+                }
+                write!(f, "\"")
+            },
+            Token::Symbol(s) => write!(f, "{}", s),
+            Token::Quotation(q) => {
+                write!(f, "[")?;
+                for (i, t) in q.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", t)?;
+                }
+                write!(f, "]")
+            },
+            Token::File(file) => write!(f, "file:{}", file.borrow().name)
+        }
+    }
+}
+
+/// The escape sequence for `c` inside a char or string literal.
+fn escape_char(c: char) -> String {
+    match c {
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        '\\' | '\'' | '"' => format!("\\{}", c),
+        c => c.to_string()
+    }
+}
+
+/// Replaces the escape sequences accepted by `literal_in`.
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some(c) => c,
+            None => '\\'
+        });
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ErrorKind {
+    /// Not enough values on the stack.
+    Underflow,
+    /// A value of the wrong type.
+    Type,
+    /// A value of the right type that the word cannot use.
+    Value,
+    Undefined,
+    Io,
+    /// Raised by `throw`.
+    User,
+    /// Raised by `abort`; `try` does not catch it.
+    Abort,
+    /// Raised by the `assert` words.
+    Assertion,
+    /// Input that is not Joy.
+    Syntax
+}
+
+impl ErrorKind {
+    const ALL: [ErrorKind; 9] = [
+        ErrorKind::Underflow, ErrorKind::Type, ErrorKind::Value, ErrorKind::Undefined,
+        ErrorKind::Io, ErrorKind::User, ErrorKind::Abort, ErrorKind::Assertion, ErrorKind::Syntax
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Underflow => "underflow",
+            ErrorKind::Type => "type",
+            ErrorKind::Value => "value",
+            ErrorKind::Undefined => "undefined",
+            ErrorKind::Io => "io",
+            ErrorKind::User => "user",
+            ErrorKind::Abort => "abort",
+            ErrorKind::Assertion => "assertion",
+            ErrorKind::Syntax => "syntax"
+        }
+    }
+}
+
+/// An error raised while running a program. `try` hands it to the program
+/// as the list `[kind "message" token]`.
+#[derive(Debug, Clone)]
+struct JoyError {
+    kind: ErrorKind,
+    message: String,
+    /// The word that failed, or the value given to `throw`.
+    token: Token
+}
+
+impl JoyError {
+    fn new(kind: ErrorKind, sym: &str, message: String) -> JoyError {
+        JoyError { kind, message, token: Token::Symbol(sym.to_string()) }
+    }
+
+    /// The error raised by `throw`. An error value caught by `try` is raised
+    /// again as it was; anything else becomes a user error.
+    fn from_token(x: Token) -> JoyError {
+        if let Token::Quotation(q) = &x {
+            if let [Token::Symbol(kind), Token::String(message), token] = q.as_slice() {
+                if let Some(&kind) = ErrorKind::ALL.iter().find(|k| k.name() == kind) {
+                    return JoyError { kind, message: message.clone(), token: token.clone() };
+                }
+            }
+        }
+        let message = match &x {
+            Token::String(s) => s.clone(),
+            _ => x.to_string()
+        };
+        JoyError { kind: ErrorKind::User, message, token: x }
+    }
+
+    fn to_token(&self) -> Token {
+        Token::Quotation(vec![
+            Token::Symbol(self.kind.name().to_string()),
+            Token::String(self.message.clone()),
+            self.token.clone()
+        ])
+    }
+}
+
+impl fmt::Display for JoyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} error: {}", self.kind.name(), self.message)
+    }
+}
+
+#[derive(Debug)]
+enum Stream {
+    /// Reads from the `Env` input.
+    Stdin,
+    /// Writes to the `Env` output.
+    Stdout,
+    Stderr,
+    Disk(io::BufReader<fs::File>),
+    /// A closed stream, or one that failed to open.
+    Closed
+}
+
+/// A stream as seen by the file words. Copies of a `Token::File` share it,
+/// like copies of a C `FILE *`.
+#[derive(Debug)]
+struct JoyFile {
+    name: String,
+    stream: Stream,
+    eof: bool,
+    error: bool
+}
+
+impl JoyFile {
+    fn new(name: &str, stream: Stream) -> Rc<RefCell<JoyFile>> {
+        Rc::new(RefCell::new(JoyFile {
+            name: name.to_string(),
+            stream,
+            eof: false,
+            error: false
+        }))
+    }
+
+    /// Opens `path` with a C `fopen` mode. Failures give a closed stream
+    /// with its error flag set.
+    fn open(path: &str, mode: &str) -> Rc<RefCell<JoyFile>> {
+        let mut options = fs::OpenOptions::new();
+        let update = mode.contains('+');
+        match mode.chars().next() {
+            Some('r') => options.read(true).write(update),
+            Some('w') => options.write(true).create(true).truncate(true).read(update),
+            Some('a') => options.append(true).create(true).read(update),
+            _ => unreachable!("checked by `fopen`")
+        };
+        let file = JoyFile::new(path, Stream::Closed);
+        match options.open(path) {
+            Ok(f) => file.borrow_mut().stream = Stream::Disk(io::BufReader::new(f)),
+            Err(_) => file.borrow_mut().error = true
+        }
+        file
+    }
+
+    fn reader<'a>(&'a mut self, env: &'a mut Env) -> Option<&'a mut dyn BufRead> {
+        match &mut self.stream {
+            Stream::Stdin => Some(&mut *env.input),
+            Stream::Disk(r) => Some(r),
+            _ => None
+        }
+    }
+
+    /// Reads up to `n` bytes, or up to and including `delim` if one is given.
+    fn read_bytes(&mut self, env: &mut Env, n: usize, delim: Option<u8>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let result = match self.reader(env) {
+            Some(r) => match delim {
+                Some(d) => r.read_until(d, &mut bytes).map(|_| ()),
+                None => r.take(n as u64).read_to_end(&mut bytes).map(|_| ())
+            },
+            None => Err(io::Error::other("stream is not readable"))
+        };
+        match result {
+            // a read that stops short has hit the end, even if it got some bytes
+            Ok(()) => if match delim {
+                Some(d) => bytes.last() != Some(&d),
+                None => bytes.len() < n
+            } {
+                self.eof = true;
+            },
+            Err(_) => self.error = true
+        }
+        bytes
+    }
+
+    /// Reads one UTF-8 encoded char, or `None` at the end of the stream.
+    fn read_char(&mut self, env: &mut Env) -> Option<char> {
+        let first = self.read_bytes(env, 1, None);
+        let len = match first.first() {
+            Some(b) if *b >= 0xf0 => 4,
+            Some(b) if *b >= 0xe0 => 3,
+            Some(b) if *b >= 0xc0 => 2,
+            Some(_) => 1,
+            None => return None
+        };
+        let mut bytes = first;
+        bytes.extend(self.read_bytes(env, len - 1, None));
+        match std::str::from_utf8(&bytes) {
+            Ok(s) => s.chars().next(),
+            Err(_) => {
+                self.error = true;
+                Some(char::REPLACEMENT_CHARACTER)
+            }
+        }
+    }
+
+    fn write_bytes(&mut self, env: &mut Env, bytes: &[u8]) {
+        let result = match &mut self.stream {
+            Stream::Stdout => env.out.write_all(bytes),
+            Stream::Stderr => io::stderr().write_all(bytes),
+            // drop read-ahead so the write lands at the logical position
+            Stream::Disk(r) => r.stream_position()
+                .and_then(|pos| r.seek(SeekFrom::Start(pos)))
+                .and_then(|_| r.get_mut().write_all(bytes)),
+            _ => Err(io::Error::other("stream is not writable"))
+        };
+        if result.is_err() {
+            self.error = true;
+        }
+    }
+
+    fn flush(&mut self, env: &mut Env) {
+        let result = match &mut self.stream {
+            Stream::Stdout => env.out.flush(),
+            Stream::Stderr => io::stderr().flush(),
+            _ => Ok(())
+        };
+        if result.is_err() {
+            self.error = true;
+        }
+    }
+
+    /// C `fseek`; returns whether it failed.
+    fn seek(&mut self, pos: i64, whence: i64) -> bool {
+        let to = match whence {
+            0 if pos >= 0 => SeekFrom::Start(pos as u64),
+            1 => SeekFrom::Current(pos),
+            2 => SeekFrom::End(pos),
+            _ => return true
+        };
+        match &mut self.stream {
+            Stream::Disk(r) => match r.seek(to) {
+                Ok(_) => {
+                    self.eof = false;
+                    false
+                },
+                Err(_) => true
+            },
+            _ => true
+        }
+    }
+
+    fn tell(&mut self) -> i64 {
+        match &mut self.stream {
+            Stream::Disk(r) => r.stream_position().map(|p| p as i64).unwrap_or(-1),
+            _ => -1
+        }
+    }
+}
+
+/// Everything a program can reach besides its stack: the user definitions and
+/// the streams used by the I/O words, which the host can redirect.
+struct Env {
+    programs: HashMap<String, Vec<Token>>,
+    out: Box<dyn Write>,
+    input: Box<dyn BufRead>,
+    /// Unparsed rest of the last line read by `get`.
+    pending: String,
+    /// The `stdin`, `stdout` and `stderr` handles.
+    std_files: [Rc<RefCell<JoyFile>>; 3],
+    /// Command line arguments for `argv`, starting with the program name.
+    argv: Vec<String>,
+    /// Seconds since the epoch for `time`; replace it for deterministic runs.
+    time: Box<dyn Fn() -> i64>,
+    /// CPU time in hundredths of a second for `clock`; replaceable as well.
+    clock: Box<dyn Fn() -> i64>,
+    /// State of the `rand` generator, set by `srand`.
+    seed: u64,
+    /// Files being loaded by `include` or `use`, innermost last.
+    loading: Vec<PathBuf>,
+    /// Files already loaded, which are not loaded again.
+    loaded: HashSet<PathBuf>,
+    /// Open `HIDE` and `MODULE` blocks, innermost last.
+    blocks: Vec<Block>,
+    /// Counter that keeps the names of hidden definitions unique.
+    hidden: usize,
+    /// Whether new definitions are checked for stack effect conflicts.
+    static_check: bool,
+    /// Whether only definitions and `include`/`use` lines are run, for
+    /// `joy check`.
+    checking: bool,
+    /// Declared stack effects of user definitions.
+    effects: HashMap<String, String>,
+    /// Whether calls to words with a declared effect check it at run time.
+    debug: bool,
+    /// Text of a `(* ... *)` comment still open at the end of the last line.
+    comment: Option<String>,
+    /// A `(** ... *)` docstring waiting for the definition it documents.
+    doc: Option<String>,
+    /// Docstrings of user definitions.
+    docs: HashMap<String, String>,
+    /// `TEST name ... END` blocks read so far, as names and lines of code,
+    /// which only `joy test` runs.
+    tests: Vec<(String, Vec<String>)>,
+    /// The `TEST` block still being read.
+    test: Option<(String, Vec<String>)>,
+    /// Definitions as the prelude made them, which `:save` leaves out.
+    prelude: HashMap<String, Vec<Token>>
+}
+
+/// A `HIDE` or `MODULE` block whose definitions are collected until its `END`.
+struct Block {
+    /// The module name, or `None` for `HIDE`.
+    module: Option<String>,
+    /// Whether definitions go to the public part (after `IN` or `PUBLIC`).
+    public: bool,
+    private: Vec<(String, Vec<Token>)>,
+    exported: Vec<(String, Vec<Token>)>,
+    /// Already renamed definitions from nested blocks.
+    hidden: Vec<(String, Vec<Token>)>,
+    /// Declared stack effects and docstrings of the definitions above.
+    effects: HashMap<String, String>,
+    docs: HashMap<String, String>
+}
+
+impl Block {
+    fn new(module: Option<String>, public: bool) -> Block {
+        Block {
+            module,
+            public,
+            private: Vec::new(),
+            exported: Vec::new(),
+            hidden: Vec::new(),
+            effects: HashMap::new(),
+            docs: HashMap::new()
+        }
+    }
+}
+
+impl Env {
+    fn new() -> Env {
+        Env::with_io(Box::new(io::stdout()), Box::new(io::stdin().lock()))
+    }
+
+    fn with_io(out: Box<dyn Write>, input: Box<dyn BufRead>) -> Env {
+        Env {
+            programs: HashMap::new(),
+            out,
+            input,
+            pending: String::new(),
+            std_files: [
+                JoyFile::new("stdin", Stream::Stdin),
+                JoyFile::new("stdout", Stream::Stdout),
+                JoyFile::new("stderr", Stream::Stderr)
+            ],
+            argv: Vec::new(),
+            time: Box::new(|| {
+                SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+            }),
+            clock: Box::new(|| {
+                let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+                unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts) };
+                ts.tv_sec * 100 + ts.tv_nsec / 10_000_000
+            }),
+            // like C, `rand` behaves as if seeded with 1 until `srand` is called
+            seed: 1,
+            loading: Vec::new(),
+            loaded: HashSet::new(),
+            blocks: Vec::new(),
+            hidden: 0,
+            static_check: true,
+            checking: false,
+            effects: HashMap::new(),
+            debug: false,
+            comment: None,
+            doc: None,
+            docs: HashMap::new(),
+            tests: Vec::new(),
+            test: None,
+            prelude: HashMap::new()
+        }
+    }
+
+    /// Next number from a 64-bit LCG, between 0 and 2^31 - 1.
+    fn rand(&mut self) -> i64 {
+        self.seed = self.seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.seed >> 33) as i64
+    }
+
+    fn write(&mut self, sym: &str, s: &str) -> Result<(), JoyError> {
+        match self.out.write_all(s.as_bytes()) {
+            Ok(()) => Ok(()),
+            Err(error) => Err(JoyError::new(ErrorKind::Io, sym, format!("cannot write output: {}", error)))
+        }
+    }
+
+    /// Reads and parses the next value from the input, for `get`.
+    fn read_token(&mut self) -> Result<Token, JoyError> {
+        loop {
+            let rest = self.pending.trim_start().to_string();
+            if !rest.is_empty() {
+                return match token(&rest) {
+                    Ok((left, tok)) => {
+                        self.pending = left.to_string();
+                        Ok(tok)
+                    },
+                    _ => Err(JoyError::new(ErrorKind::Value, "get", format!("`get` cannot parse `{}`", rest.trim_end())))
+                };
+            }
+            self.pending.clear();
+            match self.input.read_line(&mut self.pending) {
+                Ok(0) => return Err(JoyError::new(ErrorKind::Io, "get", "`get` reached the end of the input".to_string())),
+                Ok(_) => {},
+                Err(error) => return Err(JoyError::new(ErrorKind::Io, "get", format!("cannot read input: {}", error)))
+            }
+        }
+    }
+}
+
+fn sign(s: &str) -> IResult<&str, &str> {
+    recognize(opt(alt((tag("+"), tag("-")))))(s)
+}
+
+/// One or more of `digits`, allowing single `_` separators between digits.
+fn separated_digits<'a>(digits: &'static str) -> impl Fn(&'a str) -> IResult<&'a str, &'a str> {
+    recognize(tuple((
+        one_of(digits),
+        many0(preceded(opt(char('_')), one_of(digits))),
+        not(char('_'))
+    )))
+}
+
+/// Succeeds where a token may end, so that `1x` or `0x1G` is one symbol
+/// rather than a number followed by a symbol.
+fn token_end(s: &str) -> IResult<&str, ()> {
+    not(none_of(" \t\n][()'\""))(s)
 }
 
 fn maybe_signed_digits(s: &str) -> IResult<&str, &str> {
     recognize(pair(
-        opt(alt((tag("+"), tag("-")))),
-        digit1
+        sign,
+        separated_digits("0123456789")
+    ))(s)
+}
+
+fn radix_digits(s: &str) -> IResult<&str, (u32, &str)> {
+    alt((
+        pair(map(tag("0x"), |_| 16), separated_digits("0123456789abcdefABCDEF")),
+        pair(map(tag("0o"), |_| 8), separated_digits("01234567")),
+        pair(map(tag("0b"), |_| 2), separated_digits("01"))
     ))(s)
 }
 
@@ -34,30 +559,78 @@ fn floating_point(s: &str) -> IResult<&str, &str> {
     recognize(tuple((
         maybe_signed_digits,
         tag("."),
-        digit1
+        digit1,
+        opt(pair(one_of("eE"), maybe_signed_digits))
     )))(s)
 }
 
 fn decimal(s: &str) -> IResult<&str, Token> {
     map_res(
-        floating_point,
-        |s| f32::from_str(s).and_then(|f| Ok(Token::Decimal(f)))
+        terminated(floating_point, token_end),
+        |s: &str| f32::from_str(&s.replace('_', "")).map(Token::Decimal)
+    )(s)
+}
+
+fn radix_number(s: &str) -> IResult<&str, Token> {
+    map_res(
+        terminated(pair(sign, radix_digits), token_end),
+        |(sign, (radix, digits))| {
+            let digits = format!("{}{}", sign, digits.replace('_', ""));
+            i64::from_str_radix(&digits, radix).map(Token::Number)
+        }
     )(s)
 }
 
 fn number(s: &str) -> IResult<&str, Token> {
     map_res(
-        maybe_signed_digits,
-        |s| i32::from_str(s).and_then(|n| Ok(Token::Number(n)))
+        terminated(maybe_signed_digits, token_end),
+        |s: &str| i64::from_str(&s.replace('_', "")).map(Token::Number)
+    )(s)
+}
+
+fn character(s: &str) -> IResult<&str, Token> {
+    map(
+        delimited(
+            tag("'"),
+            alt((
+                preceded(char('\\'), alt((
+                    value('\n', char('n')),
+                    value('\t', char('t')),
+                    value('\r', char('r')),
+                    one_of("\\'\"")
+                ))),
+                none_of("\\")
+            )),
+            // `'a'` and `'a b'` are still strings, `'a` is a char
+            pair(not(none_of(" \t\n[]")), not(quote_closes))
+        ),
+        Token::Char
     )(s)
 }
 
+/// Succeeds when the next `'` closes a string, being followed by a
+/// delimiter, rather than starting a char.
+fn quote_closes(s: &str) -> IResult<&str, ()> {
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            },
+            '\'' if chars.next().is_none_or(|d| " \t\n[]".contains(d)) => return Ok((s, ())),
+            '\'' => break,
+            _ => {}
+        }
+    }
+    Err(nom::Err::Error((s, nom::error::ErrorKind::Verify)))
+}
+
 fn literal_in(s: &str) -> IResult<&str, &str> {
     alt((
         delimited(
             tag("'"),
             alt((
-                escaped(none_of("\\\'"), '\\', tag("'")),
+                escaped(none_of("\\\'"), '\\', one_of("\"'\\ntr")),
                 tag("")
             )),
             tag("'")
@@ -65,7 +638,7 @@ fn literal_in(s: &str) -> IResult<&str, &str> {
         delimited(
             tag("\""),
             alt((
-                escaped(none_of("\\\""), '\\', tag("\"")),
+                escaped(none_of("\\\""), '\\', one_of("\"'\\ntr")),
                 tag("")
             )),
             tag("\"")
@@ -76,27 +649,27 @@ fn literal_in(s: &str) -> IResult<&str, &str> {
 fn literal(s: &str) -> IResult<&str, Token> {
     map(
         literal_in,
-        |inn| Token::String(inn.to_string())
+        |inn| Token::String(unescape(inn))
     )(s)
 }
 
 fn boolean(s: &str) -> IResult<&str, Token> {
     map_res(
         alt((tag("true"), tag("false"))),
-        |s| bool::from_str(s).and_then(|b| Ok(Token::Boolean(b)))
+        |s| bool::from_str(s).map(Token::Boolean)
     )(s)
 }
 
 fn symbol(s: &str) -> IResult<&str, Token> {
     map(
-        many1(none_of(" ][()\'\"")),
+        many1(none_of(" \t\n][()\'\"")),
         |ss: Vec<char>| Token::Symbol(ss.into_iter().collect())
     )(s)
 }
 
 fn token(s: &str) -> IResult<&str, Token> {
     alt((
-        literal, decimal, number, boolean, symbol, list
+        character, literal, decimal, radix_number, number, boolean, symbol, list
     ))(s)
 }
 
@@ -110,28 +683,370 @@ fn expr(s: &str) -> IResult<&str, Vec<Token>> {
     )(s)
 }
 
+/// A quotation, which may spread over several lines.
 fn list(s: &str) -> IResult<&str, Token> {
     map(
-        delimited(tag("["), expr, tag("]")),
-        |elems| Token::Quotation(Box::new(elems))
+        delimited(pair(tag("["), opt(whitespace)), expr, pair(opt(whitespace), tag("]"))),
+        Token::Quotation
+    )(s)
+}
+
+/// A declared stack effect such as `(n -- n)`.
+fn effect(s: &str) -> IResult<&str, String> {
+    map(
+        recognize(delimited(char('('), many0(none_of("()")), char(')'))),
+        String::from
     )(s)
 }
 
-fn definition(s: &str) -> IResult<&str, (String, Vec<Token>)> {
+/// A definition `name == body`, optionally declaring its stack effect as in
+/// `sq (n -- n) == dup *`.
+fn definition(s: &str) -> IResult<&str, (String, Option<String>, Vec<Token>)> {
     map(
         separated_pair(
-            symbol,
+            pair(symbol, opt(preceded(whitespace, effect))),
             tuple((whitespace, tag("=="), whitespace)),
             expr
         ),
-        |(sym, b)| match sym {
-            Token::Symbol(sname) => (sname, b),
+        |((sym, effect), b)| match sym {
+            Token::Symbol(sname) => (sname, effect, b),
             _ => panic!("unreachable")
         }
     )(s)
 }
 
 
+/// Total order over all tokens, used by `compare` and for sorting. Numbers
+/// and decimals compare numerically with each other, quotations compare
+/// element by element, and values of unrelated kinds are ordered by kind.
+fn compare(a: &Token, b: &Token) -> Ordering {
+    fn rank(t: &Token) -> u8 {
+        match t {
+            Token::Boolean(_) => 0,
+            Token::Char(_) => 1,
+            Token::Number(_) | Token::Decimal(_) => 2,
+            Token::String(_) => 3,
+            Token::Symbol(_) => 4,
+            Token::Quotation(_) => 5,
+            Token::File(_) => 6
+        }
+    }
+    match (a, b) {
+        (Token::Boolean(x), Token::Boolean(y)) => x.cmp(y),
+        (Token::Char(x), Token::Char(y)) => x.cmp(y),
+        (Token::Number(x), Token::Number(y)) => x.cmp(y),
+        (Token::Decimal(x), Token::Decimal(y)) => x.total_cmp(y),
+        (Token::Number(x), Token::Decimal(y)) => (*x as f64).total_cmp(&(*y as f64)),
+        (Token::Decimal(x), Token::Number(y)) => (*x as f64).total_cmp(&(*y as f64)),
+        (Token::String(x), Token::String(y))
+        | (Token::Symbol(x), Token::Symbol(y)) => x.cmp(y),
+        (Token::Quotation(x), Token::Quotation(y)) => x.iter()
+            .zip(y.iter())
+            .map(|(a, b)| compare(a, b))
+            .find(|&ord| ord != Ordering::Equal)
+            .unwrap_or_else(|| x.len().cmp(&y.len())),
+        (Token::File(x), Token::File(y)) => Rc::as_ptr(x).cmp(&Rc::as_ptr(y)),
+        _ => rank(a).cmp(&rank(b))
+    }
+}
+
+/// The order the comparison words and `assert-eq` use: like `compare`, but
+/// floats compare as numbers do, so `-0.0` equals `0.0` and NaN is
+/// unordered, even with itself.
+fn partial_compare(a: &Token, b: &Token) -> Option<Ordering> {
+    match (a, b) {
+        (Token::Decimal(x), Token::Decimal(y)) => x.partial_cmp(y),
+        (Token::Number(x), Token::Decimal(y)) => (*x as f64).partial_cmp(&(*y as f64)),
+        (Token::Decimal(x), Token::Number(y)) => (*x as f64).partial_cmp(&(*y as f64)),
+        (Token::Quotation(x), Token::Quotation(y)) => {
+            for (a, b) in x.iter().zip(y.iter()) {
+                match partial_compare(a, b)? {
+                    Ordering::Equal => {},
+                    ord => return Some(ord)
+                }
+            }
+            Some(x.len().cmp(&y.len()))
+        },
+        _ => Some(compare(a, b))
+    }
+}
+
+/// Name of the type of `t`, as pushed by `typeof` and tested by the type
+/// predicates.
+fn type_name(t: &Token) -> &'static str {
+    match t {
+        Token::Number(_) => "integer",
+        Token::Boolean(_) => "logical",
+        Token::Decimal(_) => "float",
+        Token::Char(_) => "char",
+        Token::String(_) => "string",
+        Token::Symbol(_) => "symbol",
+        Token::Quotation(_) => "list",
+        Token::File(_) => "file"
+    }
+}
+
+/// Whether `t` is a leaf of a tree, i.e. anything but a list.
+fn is_leaf(t: &Token) -> bool {
+    !matches!(t, Token::Quotation(_))
+}
+
+fn pop(stack: &mut Vec<Token>, sym: &str) -> Result<Token, JoyError> {
+    match stack.pop() {
+        Some(t) => Ok(t),
+        None => Err(JoyError::new(ErrorKind::Underflow, sym, format!("`{}` needs more values on the stack", sym)))
+    }
+}
+
+fn type_error<T>(sym: &str, what: &str) -> Result<T, JoyError> {
+    Err(JoyError::new(ErrorKind::Type, sym, format!("`{}` needs {}", sym, what)))
+}
+
+fn overflow<T>(sym: &str) -> Result<T, JoyError> {
+    Err(JoyError::new(ErrorKind::Value, sym, format!("`{}` overflows an integer", sym)))
+}
+
+/// The message of a failed assertion, with the values in Joy syntax one
+/// above the other so they are easy to compare.
+fn differs(what: &str, expected: &str, actual: &str) -> String {
+    let show = |s: &str| if s.is_empty() { "(empty)".to_string() } else { s.to_string() };
+    format!("{} differ\n  expected: {}\n  actual:   {}", what, show(expected), show(actual))
+}
+
+/// Values in Joy syntax, bottom first.
+fn joy_text(ts: &[Token]) -> String {
+    ts.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+fn pop_string(stack: &mut Vec<Token>, sym: &str) -> Result<String, JoyError> {
+    match pop(stack, sym)? {
+        Token::String(s) => Ok(s),
+        _ => type_error(sym, "a string")
+    }
+}
+
+fn pop_number(stack: &mut Vec<Token>, sym: &str) -> Result<i64, JoyError> {
+    match pop(stack, sym)? {
+        Token::Number(n) => Ok(n),
+        _ => type_error(sym, "a number")
+    }
+}
+
+fn pop_file(stack: &mut Vec<Token>, sym: &str) -> Result<Rc<RefCell<JoyFile>>, JoyError> {
+    match pop(stack, sym)? {
+        Token::File(f) => Ok(f),
+        _ => type_error(sym, "a file")
+    }
+}
+
+/// Converts a C `tm` to Joy's time list:
+/// `[year month day hour minute second isdst yearday weekday]`, with months
+/// counted from 1 and weekdays from 1 = Monday to 7 = Sunday.
+fn tm_to_list(tm: &libc::tm) -> Token {
+    Token::Quotation(vec![
+        Token::Number(tm.tm_year as i64 + 1900),
+        Token::Number(tm.tm_mon as i64 + 1),
+        Token::Number(tm.tm_mday as i64),
+        Token::Number(tm.tm_hour as i64),
+        Token::Number(tm.tm_min as i64),
+        Token::Number(tm.tm_sec as i64),
+        Token::Boolean(tm.tm_isdst > 0),
+        Token::Number(tm.tm_yday as i64),
+        Token::Number(if tm.tm_wday == 0 { 7 } else { tm.tm_wday as i64 })
+    ])
+}
+
+fn list_to_tm(stack: &mut Vec<Token>, sym: &str) -> Result<libc::tm, JoyError> {
+    let fields = match pop(stack, sym)? {
+        Token::Quotation(q) if q.len() == 9 => q,
+        _ => return type_error(sym, "a time list of 9 elements")
+    };
+    let mut n = [0; 9];
+    for (i, field) in fields.iter().enumerate() {
+        n[i] = match field {
+            Token::Number(n) => *n as libc::c_int,
+            Token::Boolean(b) => *b as libc::c_int,
+            _ => return type_error(sym, "a time list of numbers")
+        };
+    }
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = n[0] - 1900;
+    tm.tm_mon = n[1] - 1;
+    tm.tm_mday = n[2];
+    tm.tm_hour = n[3];
+    tm.tm_min = n[4];
+    tm.tm_sec = n[5];
+    tm.tm_isdst = n[6];
+    tm.tm_yday = n[7];
+    tm.tm_wday = n[8] % 7;
+    Ok(tm)
+}
+
+fn pop_quotation(stack: &mut Vec<Token>, sym: &str) -> Result<Vec<Token>, JoyError> {
+    match pop(stack, sym)? {
+        Token::Quotation(q) => Ok(q),
+        _ => type_error(sym, "a quotation")
+    }
+}
+
+/// Pops the top `n` elements, keeping their order.
+fn split_args(stack: &mut Vec<Token>, n: usize, sym: &str) -> Result<Vec<Token>, JoyError> {
+    if stack.len() < n {
+        return Err(JoyError::new(ErrorKind::Underflow, sym, format!("`{}` needs {} arguments", sym, n)));
+    }
+    Ok(stack.split_off(stack.len() - n))
+}
+
+/// Number of values the `dip` and `app` families act on, from their names.
+fn sym_arity(sym: &str) -> usize {
+    match sym {
+        "dip" => 1,
+        "dipd" | "app2" | "unary2" => 2,
+        "dipdd" | "app3" | "unary3" => 3,
+        "app4" | "unary4" => 4,
+        _ => unreachable!()
+    }
+}
+
+fn pop_char(stack: &mut Vec<Token>, sym: &str) -> Result<char, JoyError> {
+    match pop(stack, sym)? {
+        Token::Char(c) => Ok(c),
+        _ => type_error(sym, "a char")
+    }
+}
+
+/// C `strtol`: skips leading whitespace and parses the longest valid prefix
+/// in `base`, which may be 0 to detect `0x`/`0` prefixes. Returns 0 if
+/// nothing could be parsed, and saturates at the `i64` limits when out of
+/// range.
+fn strtol(s: &str, base: u32) -> i64 {
+    let s = s.trim_start();
+    let (negative, s) = match s.chars().next() {
+        Some('-') => (true, &s[1..]),
+        Some('+') => (false, &s[1..]),
+        _ => (false, s)
+    };
+    let lower = s.to_lowercase();
+    let (base, s) = match base {
+        0 | 16 if lower.starts_with("0x") => (16, &s[2..]),
+        0 if s.starts_with('0') => (8, s),
+        0 => (10, s),
+        _ => (base, s)
+    };
+    // past i64::MAX + 1 the value only matters as "too big"
+    let limit = i64::MAX as i128 + 1;
+    let n = s.chars()
+        .map_while(|c| c.to_digit(base))
+        .fold(0i128, |n, d| (n * base as i128 + d as i128).min(limit));
+    let n = if negative { -n } else { n };
+    n.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+/// C `strtod`: parses the longest prefix of `s` that is a valid float.
+fn strtod(s: &str) -> f32 {
+    let s = s.trim_start();
+    s.char_indices()
+        .map(|(i, c)| i + c.len_utf8())
+        .rev()
+        .find_map(|end| f32::from_str(&s[..end]).ok())
+        .unwrap_or(0.0)
+}
+
+fn change_case(c: char, upper: bool) -> char {
+    let mut changed: Vec<char> = if upper {
+        c.to_uppercase().collect()
+    } else {
+        c.to_lowercase().collect()
+    };
+    if changed.len() == 1 { changed.pop().unwrap() } else { c }
+}
+
+/// Pads `body` on the left to `width` characters, like printf's `%*`.
+/// The largest width or precision `format` and `formatf` accept.
+const FORMAT_LIMIT: i64 = 4096;
+
+/// A width or precision for `format` or `formatf`, where negative means none.
+fn format_size(n: i64, sym: &str) -> Result<usize, JoyError> {
+    if n > FORMAT_LIMIT {
+        return Err(JoyError::new(ErrorKind::Value, sym, format!("`{}` width and precision must be at most {}", sym, FORMAT_LIMIT)));
+    }
+    Ok(n.max(0) as usize)
+}
+
+fn pad(body: String, width: usize) -> String {
+    let len = body.chars().count();
+    if len >= width {
+        body
+    } else {
+        format!("{}{}", " ".repeat(width - len), body)
+    }
+}
+
+/// printf-style `%*.*<mode>` for integers, as used by `format`. The
+/// precision is the minimum number of digits. `None` if `mode` is unknown.
+fn format_number(n: i64, mode: char, width: usize, precision: usize) -> Option<String> {
+    let m = n.unsigned_abs();
+    let digits = match mode {
+        'd' | 'i' => m.to_string(),
+        'o' => format!("{:o}", m),
+        'x' => format!("{:x}", m),
+        'X' => format!("{:X}", m),
+        _ => return None
+    };
+    let digits = if digits.len() < precision {
+        format!("{}{}", "0".repeat(precision - digits.len()), digits)
+    } else {
+        digits
+    };
+    Some(pad(format!("{}{}", if n < 0 { "-" } else { "" }, digits), width))
+}
+
+/// printf-style `%*.*<mode>` for floats, as used by `formatf`. `None` if
+/// `mode` is unknown.
+fn format_decimal(f: f64, mode: char, width: usize, precision: usize) -> Option<String> {
+    fn exponential(f: f64, precision: usize, upper: bool) -> String {
+        let s = format!("{:.*e}", precision, f);
+        let (mantissa, exp) = s.split_once('e').unwrap_or((&s, "0"));
+        let exp: i32 = exp.parse().unwrap_or(0);
+        let e = if upper { 'E' } else { 'e' };
+        format!("{}{}{}{:02}", mantissa, e, if exp < 0 { '-' } else { '+' }, exp.abs())
+    }
+    fn strip_zeros(s: String) -> String {
+        if !s.contains('.') {
+            return s;
+        }
+        let (mantissa, exp) = s.split_at(s.find(['e', 'E']).unwrap_or(s.len()));
+        let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+        format!("{}{}", mantissa, exp)
+    }
+    if !"fFeEgG".contains(mode) {
+        return None;
+    }
+    if !f.is_finite() {
+        // like printf: no digits, and upper case for the upper case modes
+        let body = if f.is_nan() { "nan" } else if f < 0.0 { "-inf" } else { "inf" };
+        let body = if mode.is_ascii_uppercase() { body.to_uppercase() } else { body.to_string() };
+        return Some(pad(body, width));
+    }
+    let body = match mode {
+        'f' | 'F' => format!("{:.*}", precision, f),
+        'e' | 'E' => exponential(f, precision, mode == 'E'),
+        'g' | 'G' => {
+            let p = precision.max(1);
+            // the exponent after rounding to `p` digits, as printf decides
+            let rounded = format!("{:.*e}", p - 1, f);
+            let exp: i32 = rounded.split_once('e').and_then(|(_, e)| e.parse().ok()).unwrap_or(0);
+            if exp < -4 || exp >= p as i32 {
+                strip_zeros(exponential(f, p - 1, mode == 'G'))
+            } else {
+                strip_zeros(format!("{:.*}", (p as i32 - 1 - exp) as usize, f))
+            }
+        },
+        _ => return None
+    };
+    Some(pad(body, width))
+}
+
 /*fn exec(vec: &mut Vec<Token>, mut stack: &mut Vec<Token>, mut programs: &mut HashMap<String, Vec<String>>) -> bool {
     let mut quit = false;
 
@@ -412,176 +1327,3558 @@ fn definition(s: &str) -> IResult<&str, (String, Vec<Token>)> {
     quit
 }*/
 
-fn exec(mut toks: Vec<Token>, mut stack: &mut Vec<Token>, ps: &HashMap<String, Vec<Token>>) -> bool {
+/// Every word `exec` implements itself. Keep in sync with its `match`.
+const BUILTINS: &[&str] = &[
+    "+", "-", "*", "=", "!=", "<", ">", "<=", ">=", "compare",
+    "and", "or", "xor", "not",
+    "bitand", "bitor", "bitxor", "bitnot", "shl", "shr", ">hex", ">oct", ">bin",
+    "integer", "float", "string", "char", "logical", "list", "file", "set", "leaf",
+    "user", "sametype", "typeof",
+    "strtol", "strtod", "string->list", "list->string", "upper", "lower", "trim",
+    "split-on", "join", "index-of", "substring", "replace", "starts-with", "ends-with",
+    "format", "formatf",
+    "put", "putln", ".", "putch", "putchars", "get",
+    "stdin", "stdout", "stderr", "fopen", "fclose", "fflush", "feof", "ferror",
+    "fgetch", "fgets", "fread", "fwrite", "fputs", "fputch", "fseek", "ftell",
+    "fremove", "frename",
+    "argc", "argv", "getenv", "system", "time", "clock", "localtime", "gmtime",
+    "mktime", "strftime", "rand", "srand",
+    "intern", "name", "body", "defined?", "define",
+    "id", "stack", "unstack", "newstack", "infra",
+    "rollup", "rolldown", "rotate", "rollupd", "rolldownd", "over", "pick",
+    "dupd", "popd", "swapd", "pop", "dup", "swap", "size",
+    "first", "rest", "uncons", "unswons", "cons", "swons", "concat", "null", "small",
+    "i", "ifte", "x", "app1", "dip", "dipd", "dipdd", "app11", "app12",
+    "app2", "app3", "app4", "unary2", "unary3", "unary4", "cleave", "bi", "tri",
+    "map", "filter", "split", "step", "treestep", "treerec", "treegenrec", "construct",
+    "include", "use", "try", "throw", "abort", "quit",
+    "assert", "assert-eq", "assert-stack"
+];
+
+fn exec(mut toks: Vec<Token>, stack: &mut Vec<Token>, env: &mut Env) -> Result<bool, JoyError> {
     let mut quit = false;
     toks.reverse();
-    while !toks.is_empty() {
-        let tok = toks.pop().unwrap();
+    while let Some(tok) = toks.pop() {
         match tok {
             Token::Number(_)
             | Token::Boolean(_)
             | Token::Decimal(_)
+            | Token::Char(_)
             | Token::String(_)
-            | Token::Quotation(_) => stack.push(tok),
+            | Token::Quotation(_)
+            | Token::File(_) => stack.push(tok),
             Token::Symbol(sym) => match sym.as_ref() {
                 "+" => {
-                    let a = stack.pop().unwrap();
-                    let b = stack.pop().unwrap();
+                    let a = pop(stack, &sym)?;
+                    let b = pop(stack, &sym)?;
                     stack.push(match (a,b) {
-                        (Token::Number(x), Token::Number(y)) => Token::Number(x+y),
+                        (Token::Number(x), Token::Number(y)) => match x.checked_add(y) {
+                            Some(n) => Token::Number(n),
+                            None => return overflow(&sym)
+                        },
                         (Token::Decimal(x), Token::Decimal(y)) => Token::Decimal(x+y),
-                        _ => panic!("`+` needs numbers or decimals")
+                        _ => return type_error(&sym, "numbers or decimals")
                     });
                 },
                 "-" => {
-                    let a = stack.pop().unwrap();
-                    let b = stack.pop().unwrap();
+                    let a = pop(stack, &sym)?;
+                    let b = pop(stack, &sym)?;
                     stack.push(match (a,b) {
-                        (Token::Number(x), Token::Number(y)) => Token::Number(y-x),
+                        (Token::Number(x), Token::Number(y)) => match y.checked_sub(x) {
+                            Some(n) => Token::Number(n),
+                            None => return overflow(&sym)
+                        },
                         (Token::Decimal(x), Token::Decimal(y)) => Token::Decimal(y-x),
-                        _ => panic!("`-` needs numbers or decimals")
+                        _ => return type_error(&sym, "numbers or decimals")
                     });
                 },
                 "*" => {
-                    let a = stack.pop().unwrap();
-                    let b = stack.pop().unwrap();
+                    let a = pop(stack, &sym)?;
+                    let b = pop(stack, &sym)?;
                     stack.push(match (a,b) {
-                        (Token::Number(x), Token::Number(y)) => Token::Number(x*y),
+                        (Token::Number(x), Token::Number(y)) => match x.checked_mul(y) {
+                            Some(n) => Token::Number(n),
+                            None => return overflow(&sym)
+                        },
                         (Token::Decimal(x), Token::Decimal(y)) => Token::Decimal(x*y),
-                        _ => panic!("`*` needs numbers or decimals")
+                        _ => return type_error(&sym, "numbers or decimals")
                     });
                 },
-                ">" => {
-                    let b = stack.pop().unwrap();
-                    let a = stack.pop().unwrap();
-                    stack.push(match (a,b) {
-                        (Token::Number(x), Token::Number(y)) => Token::Boolean(x>y),
-                        (Token::Decimal(x), Token::Decimal(y)) => Token::Boolean(x>y),
-                        _ => panic!("`>` needs numbers or decimals")
+                "=" | "!=" | "<" | ">" | "<=" | ">=" | "compare" => {
+                    let b = pop(stack, &sym)?;
+                    let a = pop(stack, &sym)?;
+                    let ord = partial_compare(&a, &b);
+                    stack.push(match sym.as_ref() {
+                        "=" => Token::Boolean(ord == Some(Ordering::Equal)),
+                        "!=" => Token::Boolean(ord != Some(Ordering::Equal)),
+                        "<" => Token::Boolean(ord == Some(Ordering::Less)),
+                        ">" => Token::Boolean(ord == Some(Ordering::Greater)),
+                        "<=" => Token::Boolean(matches!(ord, Some(Ordering::Less | Ordering::Equal))),
+                        ">=" => Token::Boolean(matches!(ord, Some(Ordering::Greater | Ordering::Equal))),
+                        _ => Token::Number(compare(&a, &b) as i64)
                     });
                 },
-                "<" => {
-                    let b = stack.pop().unwrap();
-                    let a = stack.pop().unwrap();
+                "and" | "or" | "xor" => {
+                    let b = pop(stack, &sym)?;
+                    let a = pop(stack, &sym)?;
                     stack.push(match (a,b) {
-                        (Token::Number(x), Token::Number(y)) => Token::Boolean(x<y),
-                        (Token::Decimal(x), Token::Decimal(y)) => Token::Boolean(x<y),
-                        _ => panic!("`<` needs numbers or decimals")
+                        (Token::Boolean(x), Token::Boolean(y)) => Token::Boolean(match sym.as_ref() {
+                            "and" => x && y,
+                            "or" => x || y,
+                            _ => x != y
+                        }),
+                        _ => return type_error(&sym, "two booleans")
                     });
                 },
-                "=" => {
-                    let b = stack.pop().unwrap();
-                    let a = stack.pop().unwrap();
+                "not" => {
+                    match pop(stack, &sym)? {
+                        Token::Boolean(x) => stack.push(Token::Boolean(!x)),
+                        _ => return type_error(&sym, "a boolean")
+                    }
+                },
+                "bitand" | "bitor" | "bitxor" => {
+                    let b = pop(stack, &sym)?;
+                    let a = pop(stack, &sym)?;
                     stack.push(match (a,b) {
-                        (Token::Number(x), Token::Number(y)) => Token::Boolean(x==y),
-                        (Token::Decimal(x), Token::Decimal(y)) => Token::Boolean(x==y),
-                        _ => panic!("`=` needs numbers or decimals")
+                        (Token::Number(x), Token::Number(y)) => Token::Number(match sym.as_ref() {
+                            "bitand" => x & y,
+                            "bitor" => x | y,
+                            _ => x ^ y
+                        }),
+                        _ => return type_error(&sym, "two numbers")
                     });
                 },
-                "pop" => {
-                    stack.pop().unwrap();
+                "bitnot" => {
+                    match pop(stack, &sym)? {
+                        Token::Number(x) => stack.push(Token::Number(!x)),
+                        _ => return type_error(&sym, "a number")
+                    }
                 },
-                "dup" => {
-                    let a = stack.pop().unwrap();
-                    stack.push(a.clone());
-                    stack.push(a);
+                "shl" | "shr" => {
+                    let n = pop(stack, &sym)?;
+                    let x = pop(stack, &sym)?;
+                    stack.push(match (x,n) {
+                        (Token::Number(x), Token::Number(n)) if (0..64).contains(&n) => Token::Number(
+                            // `shr` is an arithmetic shift, so negative numbers keep their sign.
+                            if sym == "shl" { x << n } else { x >> n }
+                        ),
+                        (Token::Number(_), Token::Number(n)) => return Err(JoyError::new(ErrorKind::Value, &sym, format!("`{}` cannot shift by {}", sym, n))),
+                        _ => return type_error(&sym, "two numbers")
+                    });
                 },
-                "swap" => {
-                    let b = stack.pop().unwrap();
-                    let a = stack.pop().unwrap();
-                    stack.push(b);
-                    stack.push(a);
+                ">hex" | ">oct" | ">bin" => {
+                    let x = match pop(stack, &sym)? {
+                        Token::Number(x) => x,
+                        _ => return type_error(&sym, "a number")
+                    };
+                    let sign = if x < 0 { "-" } else { "" };
+                    let x = x.unsigned_abs();
+                    stack.push(Token::String(match sym.as_ref() {
+                        ">hex" => format!("{}0x{:X}", sign, x),
+                        ">oct" => format!("{}0o{:o}", sign, x),
+                        _ => format!("{}0b{:b}", sign, x)
+                    }));
                 },
-                "size" => {
-                    if let Token::Quotation(q) = stack.pop().unwrap() {
-                        let len = q.len();
-                        stack.push(Token::Quotation(q));
-                        stack.push(Token::Number(len as i32));
-                    } else {
-                        panic!("`size` expects a quotation");
-                    }
+                "integer" | "float" | "string" | "char" | "logical" | "list" | "file" => {
+                    let a = pop(stack, &sym)?;
+                    stack.push(Token::Boolean(type_name(&a) == sym));
                 },
-                "i" => {
-                    if let Token::Quotation(mut q) = stack.pop().unwrap() {
-                        q.reverse();
-                        toks.extend(*q);
-                    } else {
-                        panic!("`i` expects a quotation");
+                "set" => {
+                    // there is no set type yet, so nothing is a set
+                    pop(stack, &sym)?;
+                    stack.push(Token::Boolean(false));
+                },
+                "leaf" => {
+                    let a = pop(stack, &sym)?;
+                    stack.push(Token::Boolean(is_leaf(&a)));
+                },
+                "user" => {
+                    let a = pop(stack, &sym)?;
+                    stack.push(Token::Boolean(match a {
+                        Token::Symbol(name) => env.programs.contains_key(&name),
+                        _ => false
+                    }));
+                },
+                "sametype" => {
+                    let b = pop(stack, &sym)?;
+                    let a = pop(stack, &sym)?;
+                    stack.push(Token::Boolean(type_name(&a) == type_name(&b)));
+                },
+                "typeof" => {
+                    let a = pop(stack, &sym)?;
+                    stack.push(Token::Symbol(type_name(&a).to_string()));
+                },
+                "strtol" => {
+                    let base = pop_number(stack, &sym)?;
+                    let s = pop_string(stack, &sym)?;
+                    if base != 0 && !(2..=36).contains(&base) {
+                        return Err(JoyError::new(ErrorKind::Value, &sym, format!("`strtol` cannot parse in base {}", base)));
                     }
+                    stack.push(Token::Number(strtol(&s, base as u32)));
                 },
-                "ifte" => {
-                    let else_p = match stack.pop().unwrap() {
-                        Token::Quotation(q) => q,
-                        _ => panic!("`ifte` expects an else program")
-                    };
-                    let then_p = match stack.pop().unwrap() {
-                        Token::Quotation(q) => q,
-                        _ => panic!("`ifte` expects a then program")
-                    };
-                    let if_p = match stack.pop().unwrap() {
+                "strtod" => {
+                    let s = pop_string(stack, &sym)?;
+                    stack.push(Token::Decimal(strtod(&s)));
+                },
+                "string->list" => {
+                    let s = pop_string(stack, &sym)?;
+                    stack.push(Token::Quotation(s.chars().map(Token::Char).collect()));
+                },
+                "list->string" => {
+                    let q = match pop(stack, &sym)? {
                         Token::Quotation(q) => q,
-                        _ => panic!("`ifte` expects an if program")
-                    };
-                    let mut new_stack = Vec::new();
-                    exec(*if_p, &mut new_stack, ps);
-                    match new_stack.pop().unwrap() {
-                        Token::Boolean(true) => exec(*then_p, &mut stack, ps),
-                        Token::Boolean(false) => exec(*else_p, &mut stack, ps),
-                        _ => panic!("`ifte` if program must return a boolean")
+                        _ => return type_error(&sym, "a list of chars")
                     };
+                    let s = q.into_iter().map(|c| match c {
+                        Token::Char(c) => Ok(c),
+                        _ => type_error(&sym, "a list of chars")
+                    }).collect::<Result<_, _>>()?;
+                    stack.push(Token::String(s));
                 },
-                "quit" => {
+                "upper" | "lower" => {
+                    let upper = sym == "upper";
+                    let a = pop(stack, &sym)?;
+                    stack.push(match a {
+                        Token::String(s) => Token::String(
+                            if upper { s.to_uppercase() } else { s.to_lowercase() }
+                        ),
+                        Token::Char(c) => Token::Char(change_case(c, upper)),
+                        _ => return type_error(&sym, "a string or a char")
+                    });
+                },
+                "trim" => {
+                    let s = pop_string(stack, &sym)?;
+                    stack.push(Token::String(s.trim().to_string()));
+                },
+                "split-on" => {
+                    let sep = match pop(stack, &sym)? {
+                        Token::String(s) => s,
+                        Token::Char(c) => c.to_string(),
+                        _ => return type_error(&sym, "a string or char separator")
+                    };
+                    let s = pop_string(stack, &sym)?;
+                    let parts: Vec<Token> = if sep.is_empty() {
+                        s.chars().map(|c| Token::String(c.to_string())).collect()
+                    } else {
+                        s.split(sep.as_str()).map(|p| Token::String(p.to_string())).collect()
+                    };
+                    stack.push(Token::Quotation(parts));
+                },
+                "join" => {
+                    let sep = match pop(stack, &sym)? {
+                        Token::String(s) => s,
+                        Token::Char(c) => c.to_string(),
+                        _ => return type_error(&sym, "a string or char separator")
+                    };
+                    let parts: Vec<String> = match pop(stack, &sym)? {
+                        Token::Quotation(q) => q.into_iter().map(|t| match t {
+                            Token::String(s) => Ok(s),
+                            Token::Char(c) => Ok(c.to_string()),
+                            _ => type_error(&sym, "a list of strings")
+                        }).collect::<Result<_, _>>()?,
+                        _ => return type_error(&sym, "a list of strings")
+                    };
+                    stack.push(Token::String(parts.join(&sep)));
+                },
+                "index-of" => {
+                    let needle = match pop(stack, &sym)? {
+                        Token::String(s) => s,
+                        Token::Char(c) => c.to_string(),
+                        _ => return type_error(&sym, "a string or char to look for")
+                    };
+                    let s = pop_string(stack, &sym)?;
+                    stack.push(Token::Number(match s.find(&needle) {
+                        Some(i) => s[..i].chars().count() as i64,
+                        None => -1
+                    }));
+                },
+                "substring" => {
+                    let len = pop_number(stack, &sym)?;
+                    let start = pop_number(stack, &sym)?;
+                    let s = pop_string(stack, &sym)?;
+                    stack.push(Token::String(s.chars()
+                        .skip(start.max(0) as usize)
+                        .take(len.max(0) as usize)
+                        .collect()));
+                },
+                "replace" => {
+                    let to = pop_string(stack, &sym)?;
+                    let from = pop_string(stack, &sym)?;
+                    let s = pop_string(stack, &sym)?;
+                    if from.is_empty() {
+                        return Err(JoyError::new(ErrorKind::Value, &sym, "`replace` cannot replace an empty string".to_string()));
+                    }
+                    stack.push(Token::String(s.replace(&from, &to)));
+                },
+                "starts-with" | "ends-with" => {
+                    let affix = pop_string(stack, &sym)?;
+                    let s = pop_string(stack, &sym)?;
+                    stack.push(Token::Boolean(if sym == "starts-with" {
+                        s.starts_with(&affix)
+                    } else {
+                        s.ends_with(&affix)
+                    }));
+                },
+                "format" => {
+                    let precision = pop_number(stack, &sym)?;
+                    let width = pop_number(stack, &sym)?;
+                    let mode = pop_char(stack, &sym)?;
+                    let n = pop_number(stack, &sym)?;
+                    match format_number(n, mode, format_size(width, &sym)?, format_size(precision, &sym)?) {
+                        Some(s) => stack.push(Token::String(s)),
+                        None => return Err(JoyError::new(ErrorKind::Value, &sym, format!("`format` does not know mode '{}'", mode)))
+                    }
+                },
+                "formatf" => {
+                    let precision = pop_number(stack, &sym)?;
+                    let width = pop_number(stack, &sym)?;
+                    let mode = pop_char(stack, &sym)?;
+                    let f = match pop(stack, &sym)? {
+                        Token::Decimal(f) => f as f64,
+                        Token::Number(n) => n as f64,
+                        _ => return type_error(&sym, "a float")
+                    };
+                    match format_decimal(f, mode, format_size(width, &sym)?, format_size(precision, &sym)?) {
+                        Some(s) => stack.push(Token::String(s)),
+                        None => return Err(JoyError::new(ErrorKind::Value, &sym, format!("`formatf` does not know mode '{}'", mode)))
+                    }
+                },
+                "put" | "putln" | "." => {
+                    let a = pop(stack, &sym)?;
+                    let end = if sym == "put" { " " } else { "\n" };
+                    env.write(&sym, &format!("{}{}", a, end))?;
+                },
+                "putch" => {
+                    let c = pop_char(stack, &sym)?;
+                    env.write(&sym, &c.to_string())?;
+                },
+                "putchars" => {
+                    let s = pop_string(stack, &sym)?;
+                    env.write(&sym, &s)?;
+                },
+                "get" => {
+                    let tok = env.read_token()?;
+                    stack.push(tok);
+                },
+                "stdin" | "stdout" | "stderr" => {
+                    let i = ["stdin", "stdout", "stderr"].iter().position(|&n| n == sym).unwrap();
+                    stack.push(Token::File(env.std_files[i].clone()));
+                },
+                "fopen" => {
+                    let mode = pop_string(stack, &sym)?;
+                    let path = pop_string(stack, &sym)?;
+                    if !matches!(mode.chars().next(), Some('r' | 'w' | 'a')) {
+                        return Err(JoyError::new(ErrorKind::Value, &sym, format!("`fopen` does not know mode \"{}\"", mode)));
+                    }
+                    stack.push(Token::File(JoyFile::open(&path, &mode)));
+                },
+                "fclose" => {
+                    let f = pop_file(stack, &sym)?;
+                    f.borrow_mut().flush(env);
+                    f.borrow_mut().stream = Stream::Closed;
+                },
+                "fflush" => {
+                    let f = pop_file(stack, &sym)?;
+                    f.borrow_mut().flush(env);
+                    stack.push(Token::File(f));
+                },
+                "feof" | "ferror" => {
+                    let f = pop_file(stack, &sym)?;
+                    let flag = if sym == "feof" { f.borrow().eof } else { f.borrow().error };
+                    stack.push(Token::File(f));
+                    stack.push(Token::Boolean(flag));
+                },
+                "fgetch" => {
+                    let f = pop_file(stack, &sym)?;
+                    let c = f.borrow_mut().read_char(env);
+                    stack.push(Token::File(f));
+                    // like C, the end of the stream reads as -1
+                    stack.push(c.map(Token::Char).unwrap_or(Token::Number(-1)));
+                },
+                "fgets" => {
+                    let f = pop_file(stack, &sym)?;
+                    let line = f.borrow_mut().read_bytes(env, 0, Some(b'\n'));
+                    stack.push(Token::File(f));
+                    stack.push(Token::String(String::from_utf8_lossy(&line).into_owned()));
+                },
+                "fread" => {
+                    let n = pop_number(stack, &sym)?;
+                    let f = pop_file(stack, &sym)?;
+                    let bytes = f.borrow_mut().read_bytes(env, n.max(0) as usize, None);
+                    stack.push(Token::File(f));
+                    stack.push(Token::Quotation(bytes.into_iter().map(|b| Token::Number(b as i64)).collect()));
+                },
+                "fwrite" => {
+                    let bytes: Vec<u8> = match pop(stack, &sym)? {
+                        Token::Quotation(q) => q.into_iter().map(|t| match t {
+                            Token::Number(n) => u8::try_from(n).map_err(|_| JoyError::new(
+                                ErrorKind::Value, &sym, format!("`{}` needs bytes from 0 to 255, not {}", sym, n)
+                            )),
+                            _ => type_error(&sym, "a list of numbers")
+                        }).collect::<Result<_, _>>()?,
+                        _ => return type_error(&sym, "a list of numbers")
+                    };
+                    let f = pop_file(stack, &sym)?;
+                    f.borrow_mut().write_bytes(env, &bytes);
+                    stack.push(Token::File(f));
+                },
+                "fputs" => {
+                    let s = pop_string(stack, &sym)?;
+                    let f = pop_file(stack, &sym)?;
+                    f.borrow_mut().write_bytes(env, s.as_bytes());
+                    stack.push(Token::File(f));
+                },
+                "fputch" => {
+                    let c = pop_char(stack, &sym)?;
+                    let f = pop_file(stack, &sym)?;
+                    f.borrow_mut().write_bytes(env, c.to_string().as_bytes());
+                    stack.push(Token::File(f));
+                },
+                "fseek" => {
+                    let whence = pop_number(stack, &sym)?;
+                    let pos = pop_number(stack, &sym)?;
+                    let f = pop_file(stack, &sym)?;
+                    let failed = f.borrow_mut().seek(pos, whence);
+                    stack.push(Token::File(f));
+                    stack.push(Token::Boolean(failed));
+                },
+                "ftell" => {
+                    let f = pop_file(stack, &sym)?;
+                    let pos = f.borrow_mut().tell();
+                    stack.push(Token::File(f));
+                    stack.push(Token::Number(pos));
+                },
+                "fremove" => {
+                    let path = pop_string(stack, &sym)?;
+                    stack.push(Token::Boolean(fs::remove_file(path).is_ok()));
+                },
+                "frename" => {
+                    let to = pop_string(stack, &sym)?;
+                    let from = pop_string(stack, &sym)?;
+                    stack.push(Token::Boolean(fs::rename(from, to).is_ok()));
+                },
+                "argc" => {
+                    stack.push(Token::Number(env.argv.len() as i64));
+                },
+                "argv" => {
+                    stack.push(Token::Quotation(env.argv.iter().map(|a| Token::String(a.clone())).collect()));
+                },
+                "getenv" => {
+                    let name = pop_string(stack, &sym)?;
+                    stack.push(Token::String(std::env::var(name).unwrap_or_default()));
+                },
+                "system" => {
+                    let cmd = pop_string(stack, &sym)?;
+                    env.out.flush().ok();
+                    let status = Command::new("sh").arg("-c").arg(&cmd).status();
+                    stack.push(Token::Number(match status {
+                        Ok(status) => status.code().unwrap_or(-1) as i64,
+                        Err(_) => -1
+                    }));
+                },
+                "time" => {
+                    stack.push(Token::Number((env.time)()));
+                },
+                "clock" => {
+                    stack.push(Token::Number((env.clock)()));
+                },
+                "localtime" | "gmtime" => {
+                    let t = pop_number(stack, &sym)? as libc::time_t;
+                    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+                    let ok = unsafe {
+                        if sym == "localtime" {
+                            !libc::localtime_r(&t, &mut tm).is_null()
+                        } else {
+                            !libc::gmtime_r(&t, &mut tm).is_null()
+                        }
+                    };
+                    if !ok {
+                        return Err(JoyError::new(ErrorKind::Value, &sym, format!("`{}` cannot convert {}", sym, t)));
+                    }
+                    stack.push(tm_to_list(&tm));
+                },
+                "mktime" => {
+                    let mut tm = list_to_tm(stack, &sym)?;
+                    stack.push(Token::Number(unsafe { libc::mktime(&mut tm) } as i64));
+                },
+                "strftime" => {
+                    let format = pop_string(stack, &sym)?;
+                    let tm = list_to_tm(stack, &sym)?;
+                    let format = match CString::new(format) {
+                        Ok(f) => f,
+                        Err(_) => return Err(JoyError::new(ErrorKind::Value, &sym, "`strftime` format cannot contain NUL".to_string()))
+                    };
+                    // 0 means the buffer was too small, or that the result is
+                    // empty, so grow it a while before believing the latter
+                    let mut buf = vec![0u8; 256];
+                    loop {
+                        let len = unsafe {
+                            libc::strftime(buf.as_mut_ptr() as *mut libc::c_char, buf.len(), format.as_ptr(), &tm)
+                        };
+                        if len > 0 || buf.len() >= 1 << 20 {
+                            buf.truncate(len);
+                            break;
+                        }
+                        buf.resize(buf.len() * 2, 0);
+                    }
+                    stack.push(Token::String(String::from_utf8_lossy(&buf).into_owned()));
+                },
+                "rand" => {
+                    let r = env.rand();
+                    stack.push(Token::Number(r));
+                },
+                "srand" => {
+                    env.seed = pop_number(stack, &sym)? as u64;
+                },
+                "intern" => {
+                    let s = pop_string(stack, &sym)?;
+                    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || "[]()'\"".contains(c)) {
+                        return Err(JoyError::new(ErrorKind::Value, &sym, format!("`intern` cannot make a symbol of \"{}\"", s)));
+                    }
+                    stack.push(Token::Symbol(s));
+                },
+                "name" => {
+                    let a = pop(stack, &sym)?;
+                    stack.push(Token::String(match a {
+                        Token::Symbol(s) => s,
+                        // like Joy, literals are named by their type
+                        _ => type_name(&a).to_string()
+                    }));
+                },
+                "body" => {
+                    let name = match pop(stack, &sym)? {
+                        Token::Symbol(s) => s,
+                        _ => return type_error(&sym, "a symbol")
+                    };
+                    match env.programs.get(&name) {
+                        Some(p) => stack.push(Token::Quotation(p.clone())),
+                        None => return Err(JoyError::new(ErrorKind::Undefined, &sym, format!("`{}` is not a user definition", name)))
+                    }
+                },
+                "defined?" => {
+                    let a = pop(stack, &sym)?;
+                    stack.push(Token::Boolean(match a {
+                        Token::Symbol(name) => env.programs.contains_key(&name) || BUILTINS.contains(&name.as_str()),
+                        _ => false
+                    }));
+                },
+                "define" => {
+                    let p = pop_quotation(stack, &sym)?;
+                    let name = match pop(stack, &sym)? {
+                        Token::Symbol(s) => s,
+                        _ => return type_error(&sym, "a symbol and a quotation")
+                    };
+                    if BUILTINS.contains(&name.as_str()) {
+                        return Err(JoyError::new(ErrorKind::Value, &sym, format!("cannot redefine the builtin `{}`", name)));
+                    }
+                    env.effects.remove(&name);
+                    env.docs.remove(&name);
+                    env.programs.insert(name.clone(), p);
+                    report_definition(&name, env);
+                },
+                "include" => {
+                    // takes the following string literal, or a string on the stack
+                    let path = match toks.last() {
+                        Some(Token::String(_)) => match toks.pop() {
+                            Some(Token::String(s)) => s,
+                            _ => unreachable!()
+                        },
+                        _ => pop_string(stack, &sym)?
+                    };
+                    let path = match env.loading.last().and_then(|f| f.parent()) {
+                        Some(dir) => dir.join(path),
+                        None => PathBuf::from(path)
+                    };
+                    if include_file(&path, stack, env)? {
+                        quit = true;
+                        break;
+                    }
+                },
+                "use" => {
+                    let name = match toks.pop() {
+                        Some(Token::Symbol(s)) => s,
+                        _ => return Err(JoyError::new(ErrorKind::Value, &sym, "`use` must be followed by a library name".to_string()))
+                    };
+                    let path = find_library(&name).ok_or_else(|| {
+                        JoyError::new(ErrorKind::Io, &sym, format!("cannot find `{}.joy` on JOYPATH", name))
+                    })?;
+                    if include_file(&path, stack, env)? {
+                        quit = true;
+                        break;
+                    }
+                },
+                "id" => {},
+                "stack" => {
+                    let list = stack.iter().rev().cloned().collect();
+                    stack.push(Token::Quotation(list));
+                },
+                "unstack" => {
+                    match pop(stack, &sym)? {
+                        Token::Quotation(mut q) => {
+                            q.reverse();
+                            *stack = q;
+                        },
+                        _ => return type_error(&sym, "a list")
+                    }
+                },
+                "newstack" => {
+                    stack.clear();
+                },
+                "infra" => {
+                    let p = match pop(stack, &sym)? {
+                        Token::Quotation(q) => q,
+                        _ => return type_error(&sym, "a program")
+                    };
+                    let mut new_stack = match pop(stack, &sym)? {
+                        Token::Quotation(q) => q,
+                        _ => return type_error(&sym, "a list to use as the stack")
+                    };
+                    new_stack.reverse();
+                    quit = exec(p, &mut new_stack, env)?;
+                    new_stack.reverse();
+                    stack.push(Token::Quotation(new_stack));
+                },
+                "rollup" | "rolldown" | "rotate" => {
+                    let z = pop(stack, &sym)?;
+                    let y = pop(stack, &sym)?;
+                    let x = pop(stack, &sym)?;
+                    stack.extend(match sym.as_ref() {
+                        "rollup" => [z, x, y],
+                        "rolldown" => [y, z, x],
+                        _ => [z, y, x]
+                    });
+                },
+                "rollupd" | "rolldownd" => {
+                    let w = pop(stack, &sym)?;
+                    let z = pop(stack, &sym)?;
+                    let y = pop(stack, &sym)?;
+                    let x = pop(stack, &sym)?;
+                    stack.extend(if sym == "rollupd" { [z, x, y, w] } else { [y, z, x, w] });
+                },
+                "over" => {
+                    let y = pop(stack, &sym)?;
+                    let x = pop(stack, &sym)?;
+                    stack.push(x.clone());
+                    stack.push(y);
+                    stack.push(x);
+                },
+                "pick" => {
+                    let n = pop_number(stack, &sym)?;
+                    if n < 0 {
+                        return Err(JoyError::new(ErrorKind::Value, &sym, format!("`pick` cannot pick {}", n)));
+                    }
+                    if n as usize >= stack.len() {
+                        return Err(JoyError::new(ErrorKind::Underflow, &sym, format!("`pick` needs at least {} more elements", n.saturating_add(1))));
+                    }
+                    let x = stack[stack.len() - 1 - n as usize].clone();
+                    stack.push(x);
+                },
+                "dupd" => {
+                    let y = pop(stack, &sym)?;
+                    let x = pop(stack, &sym)?;
+                    stack.push(x.clone());
+                    stack.push(x);
+                    stack.push(y);
+                },
+                "popd" => {
+                    let y = pop(stack, &sym)?;
+                    pop(stack, &sym)?;
+                    stack.push(y);
+                },
+                "swapd" => {
+                    let z = pop(stack, &sym)?;
+                    let y = pop(stack, &sym)?;
+                    let x = pop(stack, &sym)?;
+                    stack.extend([y, x, z]);
+                },
+                "pop" => {
+                    pop(stack, &sym)?;
+                },
+                "dup" => {
+                    let a = pop(stack, &sym)?;
+                    stack.push(a.clone());
+                    stack.push(a);
+                },
+                "swap" => {
+                    let b = pop(stack, &sym)?;
+                    let a = pop(stack, &sym)?;
+                    stack.push(b);
+                    stack.push(a);
+                },
+                "size" => {
+                    if let Token::Quotation(q) = pop(stack, &sym)? {
+                        let len = q.len();
+                        stack.push(Token::Quotation(q));
+                        stack.push(Token::Number(len as i64));
+                    } else {
+                        return Err(JoyError::new(ErrorKind::Type, &sym, "`size` expects a quotation".to_string()));
+                    }
+                },
+                "first" | "rest" | "uncons" | "unswons" => {
+                    let mut q = pop_quotation(stack, &sym)?;
+                    if q.is_empty() {
+                        return Err(JoyError::new(ErrorKind::Value, &sym, format!("`{}` needs a non-empty list", sym)));
+                    }
+                    let x = q.remove(0);
+                    match sym.as_ref() {
+                        "first" => stack.push(x),
+                        "rest" => stack.push(Token::Quotation(q)),
+                        "uncons" => stack.extend([x, Token::Quotation(q)]),
+                        _ => stack.extend([Token::Quotation(q), x])
+                    }
+                },
+                "cons" => {
+                    let mut q = pop_quotation(stack, &sym)?;
+                    let x = pop(stack, &sym)?;
+                    q.insert(0, x);
+                    stack.push(Token::Quotation(q));
+                },
+                "swons" => {
+                    let x = pop(stack, &sym)?;
+                    let mut q = pop_quotation(stack, &sym)?;
+                    q.insert(0, x);
+                    stack.push(Token::Quotation(q));
+                },
+                "concat" => {
+                    let b = pop_quotation(stack, &sym)?;
+                    let mut a = pop_quotation(stack, &sym)?;
+                    a.extend(b);
+                    stack.push(Token::Quotation(a));
+                },
+                "null" | "small" => {
+                    let limit = if sym == "null" { 1 } else { 2 };
+                    let a = pop(stack, &sym)?;
+                    stack.push(Token::Boolean(match a {
+                        Token::Quotation(q) => q.len() < limit,
+                        Token::String(s) => s.chars().count() < limit,
+                        Token::Number(n) => (0..limit as i64).contains(&n),
+                        _ => return type_error(&sym, "a list, string or number")
+                    }));
+                },
+                "i" => {
+                    if let Token::Quotation(mut q) = pop(stack, &sym)? {
+                        q.reverse();
+                        toks.extend(q);
+                    } else {
+                        return Err(JoyError::new(ErrorKind::Type, &sym, "`i` expects a quotation".to_string()));
+                    }
+                },
+                "ifte" => {
+                    let else_p = match pop(stack, &sym)? {
+                        Token::Quotation(q) => q,
+                        _ => return Err(JoyError::new(ErrorKind::Type, &sym, "`ifte` expects an else program".to_string()))
+                    };
+                    let then_p = match pop(stack, &sym)? {
+                        Token::Quotation(q) => q,
+                        _ => return Err(JoyError::new(ErrorKind::Type, &sym, "`ifte` expects a then program".to_string()))
+                    };
+                    let if_p = match pop(stack, &sym)? {
+                        Token::Quotation(q) => q,
+                        _ => return Err(JoyError::new(ErrorKind::Type, &sym, "`ifte` expects an if program".to_string()))
+                    };
+                    quit = match exec_top(if_p, stack.clone(), env, &sym)? {
+                        Token::Boolean(true) => exec(then_p, stack, env)?,
+                        Token::Boolean(false) => exec(else_p, stack, env)?,
+                        _ => return Err(JoyError::new(ErrorKind::Type, &sym, "`ifte` if program must return a boolean".to_string()))
+                    };
+                },
+                "x" => {
+                    let mut p = pop_quotation(stack, &sym)?;
+                    stack.push(Token::Quotation(p.clone()));
+                    p.reverse();
+                    toks.extend(p);
+                },
+                "app1" => {
+                    let mut p = pop_quotation(stack, &sym)?;
+                    p.reverse();
+                    toks.extend(p);
+                },
+                "dip" | "dipd" | "dipdd" => {
+                    let p = pop_quotation(stack, &sym)?;
+                    let saved = split_args(stack, sym_arity(&sym), &sym)?;
+                    quit = exec(p, stack, env)?;
+                    stack.extend(saved);
+                },
+                "app11" => {
+                    let p = pop_quotation(stack, &sym)?;
+                    let r = exec_top(p, stack.clone(), env, &sym)?;
+                    split_args(stack, 2, &sym)?;
+                    stack.push(r);
+                },
+                "app12" => {
+                    let p = pop_quotation(stack, &sym)?;
+                    let ys = split_args(stack, 2, &sym)?;
+                    let results: Vec<Token> = ys.into_iter()
+                        .map(|y| exec_top(p.clone(), [stack.as_slice(), &[y]].concat(), env, &sym))
+                        .collect::<Result<_, _>>()?;
+                    // drop X from under the results
+                    pop(stack, &sym)?;
+                    stack.extend(results);
+                },
+                "app2" | "app3" | "app4" | "unary2" | "unary3" | "unary4" => {
+                    let p = pop_quotation(stack, &sym)?;
+                    let xs = split_args(stack, sym_arity(&sym), &sym)?;
+                    let results: Vec<Token> = xs.into_iter()
+                        .map(|x| exec_top(p.clone(), [stack.as_slice(), &[x]].concat(), env, &sym))
+                        .collect::<Result<_, _>>()?;
+                    stack.extend(results);
+                },
+                "cleave" | "bi" | "tri" => {
+                    let ps = split_args(stack, if sym == "tri" { 3 } else { 2 }, &sym)?;
+                    let x = pop(stack, &sym)?;
+                    let results: Vec<Token> = ps.into_iter()
+                        .map(|p| match p {
+                            Token::Quotation(q) => exec_top(q, [stack.as_slice(), std::slice::from_ref(&x)].concat(), env, &sym),
+                            _ => type_error(&sym, "programs")
+                        })
+                        .collect::<Result<_, _>>()?;
+                    stack.extend(results);
+                },
+                "map" => {
+                    let p = pop_quotation(stack, &sym)?;
+                    let mut list = pop_quotation(stack, &sym)?;
+                    list.reverse();
+                    queue_map(&mut toks, stack, stack.clone(), Vec::new(), list, p);
+                },
+                MAP_FRAME => {
+                    let p = pop_quotation(stack, &sym)?;
+                    let rest = pop_quotation(stack, &sym)?;
+                    let mut mapped = pop_quotation(stack, &sym)?;
+                    let saved = pop_quotation(stack, &sym)?;
+                    match stack.pop() {
+                        Some(r) => mapped.push(r),
+                        None => return Err(JoyError::new(ErrorKind::Underflow, "map", "`map` program left nothing on the stack".to_string()))
+                    }
+                    *stack = saved.clone();
+                    queue_map(&mut toks, stack, saved, mapped, rest, p);
+                },
+                "filter" | "split" => {
+                    let p = pop_quotation(stack, &sym)?;
+                    let list = pop_quotation(stack, &sym)?;
+                    let mut passed = Vec::new();
+                    let mut failed = Vec::new();
+                    for x in list {
+                        match exec_top(p.clone(), [stack.as_slice(), std::slice::from_ref(&x)].concat(), env, &sym)? {
+                            Token::Boolean(true) => passed.push(x),
+                            Token::Boolean(false) => failed.push(x),
+                            _ => return type_error(&sym, "a test that leaves a boolean")
+                        }
+                    }
+                    stack.push(Token::Quotation(passed));
+                    if sym == "split" {
+                        stack.push(Token::Quotation(failed));
+                    }
+                },
+                "step" => {
+                    let p = pop_quotation(stack, &sym)?;
+                    let list = pop_quotation(stack, &sym)?;
+                    for x in list {
+                        stack.push(x);
+                        if exec(p.clone(), stack, env)? {
+                            quit = true;
+                            break;
+                        }
+                    }
+                },
+                "treestep" => {
+                    let p = pop_quotation(stack, &sym)?;
+                    // walk the tree with our own stack of pending nodes, so
+                    // deep trees don't recurse natively
+                    let mut pending = vec![pop(stack, &sym)?];
+                    while let Some(node) = pending.pop() {
+                        match node {
+                            Token::Quotation(mut children) => {
+                                children.reverse();
+                                pending.extend(children);
+                            },
+                            leaf => {
+                                stack.push(leaf);
+                                if exec(p.clone(), stack, env)? {
+                                    quit = true;
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                },
+                "treerec" | "treegenrec" => {
+                    // leaves get O (or O1); other nodes get C with the whole
+                    // combinator quoted on top, to recurse into as it likes.
+                    // The recursion is queued onto `toks` like `i` does, so
+                    // it doesn't grow the native stack.
+                    let n = if sym == "treerec" { 2 } else { 3 };
+                    let mut ps: Vec<Vec<Token>> = split_args(stack, n, &sym)?.into_iter().map(|p| match p {
+                        Token::Quotation(q) => Ok(q),
+                        _ => type_error(&sym, "programs")
+                    }).collect::<Result<_, _>>()?;
+                    let tree = pop(stack, &sym)?;
+                    let mut next = if is_leaf(&tree) {
+                        stack.push(tree);
+                        ps.swap_remove(0)
+                    } else {
+                        stack.push(tree);
+                        let c = ps.pop().unwrap();
+                        let mut again: Vec<Token> = ps.iter().cloned().map(Token::Quotation).collect();
+                        again.push(Token::Quotation(c.clone()));
+                        again.push(Token::Symbol(sym.clone()));
+                        let mut next = if n == 3 { ps.pop().unwrap() } else { Vec::new() };
+                        next.push(Token::Quotation(again));
+                        next.extend(c);
+                        next
+                    };
+                    next.reverse();
+                    toks.extend(next);
+                },
+                "construct" => {
+                    let ps = pop_quotation(stack, &sym)?;
+                    let p = pop_quotation(stack, &sym)?;
+                    let mut after = stack.clone();
+                    exec(p, &mut after, env)?;
+                    for p in ps {
+                        let p = match p {
+                            Token::Quotation(q) => q,
+                            _ => return type_error(&sym, "a list of programs")
+                        };
+                        let r = exec_top(p, after.clone(), env, &sym)?;
+                        stack.push(r);
+                    }
+                },
+                "try" => {
+                    let handler = pop_quotation(stack, &sym)?;
+                    let body = pop_quotation(stack, &sym)?;
+                    let saved = stack.clone();
+                    quit = match exec(body, stack, env) {
+                        Ok(quit) => quit,
+                        Err(error) if error.kind != ErrorKind::Abort => {
+                            *stack = saved;
+                            stack.push(error.to_token());
+                            exec(handler, stack, env)?
+                        },
+                        Err(error) => return Err(error)
+                    };
+                },
+                "throw" => {
+                    let x = pop(stack, &sym)?;
+                    return Err(JoyError::from_token(x));
+                },
+                "abort" => {
+                    return Err(JoyError::new(ErrorKind::Abort, &sym, "aborted".to_string()));
+                },
+                "quit" => {
                     quit = true;
                     break;
                 },
-                _ => match ps.get(&sym) {
-                    Some(p) => {
-                        quit = exec(p.clone(), &mut stack, ps);
+                "assert" => match pop(stack, &sym)? {
+                    Token::Boolean(true) => {},
+                    Token::Boolean(false) => {
+                        return Err(JoyError::new(ErrorKind::Assertion, &sym, "assertion failed".to_string()));
+                    },
+                    _ => return type_error(&sym, "a boolean")
+                },
+                "assert-eq" => {
+                    let expected = pop(stack, &sym)?;
+                    let actual = pop(stack, &sym)?;
+                    if partial_compare(&actual, &expected) != Some(Ordering::Equal) {
+                        return Err(JoyError::new(ErrorKind::Assertion, &sym, differs("values", &expected.to_string(), &actual.to_string())));
+                    }
+                },
+                "assert-stack" => {
+                    let expected = pop_quotation(stack, &sym)?;
+                    let same = expected.len() == stack.len()
+                        && expected.iter().zip(stack.iter()).all(|(e, a)| partial_compare(e, a) == Some(Ordering::Equal));
+                    if !same {
+                        return Err(JoyError::new(ErrorKind::Assertion, &sym, differs("stacks", &joy_text(&expected), &joy_text(stack))));
+                    }
+                },
+                _ => match env.programs.get(&sym).cloned() {
+                    Some(p) => match env.effects.get(&sym).cloned().filter(|_| env.debug) {
+                        Some(effect) => {
+                            let depth = assert_effect(&sym, &effect, stack, None)?;
+                            quit = exec(p, stack, env)?;
+                            if !quit {
+                                assert_effect(&sym, &effect, stack, Some(depth))?;
+                            }
+                        },
+                        None => {
+                            quit = exec(p, stack, env)?;
+                        }
                     },
                     _ => {
-                        println!("undefined symbol `{}`", sym);
-                        break;
+                        return Err(JoyError::new(ErrorKind::Undefined, &sym, format!("undefined symbol `{}`", sym)));
                     }
                 }
             }
         }
     }
-    quit
+    Ok(quit)
 }
 
-fn main() {
-    println!("    a joy interpreter");
-    let mut stack = Vec::new();
-    let mut programs = HashMap::new();
-    let mut quit = false;
+/// The word `map` queues after each application of its program, to collect
+/// the result and start the next one. It cannot be written in Joy source.
+const MAP_FRAME: &str = "map (frame)";
 
-    while !quit {
-        print!("> ");
-        io::stdout().flush().unwrap();
-        let mut input = String::new();
-        match io::stdin().read_line(&mut input) {
-            Err(error) => panic!("error: {}", error),
-            _ => {},
+/// Queues `map`'s program on the next element of `rest`, which is kept last
+/// first, or pushes the mapped list when there is none. The state rides on
+/// `toks` rather than the native stack, so programs that map recursively,
+/// like the usual `treerec` bodies, can go as deep as the tree.
+fn queue_map(toks: &mut Vec<Token>, stack: &mut Vec<Token>, saved: Vec<Token>, mapped: Vec<Token>, mut rest: Vec<Token>, p: Vec<Token>) {
+    match rest.pop() {
+        Some(x) => {
+            stack.push(x);
+            toks.push(Token::Symbol(MAP_FRAME.to_string()));
+            toks.push(Token::Quotation(p.clone()));
+            toks.push(Token::Quotation(rest));
+            toks.push(Token::Quotation(mapped));
+            toks.push(Token::Quotation(saved));
+            toks.extend(p.into_iter().rev());
+        },
+        None => stack.push(Token::Quotation(mapped))
+    }
+}
+
+/// Runs `p` on `stack`, which is a copy of the real one, and returns the value
+/// it leaves on top. Conditions and the application combinators use this to
+/// compute a result without disturbing the stack.
+fn exec_top(p: Vec<Token>, mut stack: Vec<Token>, env: &mut Env, sym: &str) -> Result<Token, JoyError> {
+    exec(p, &mut stack, env)?;
+    match stack.pop() {
+        Some(t) => Ok(t),
+        None => Err(JoyError::new(ErrorKind::Underflow, sym, format!("`{}` program left nothing on the stack", sym)))
+    }
+}
+
+/// Runs a Joy source file once: later calls for the same file do nothing,
+/// and including a file that is still being loaded is an error.
+fn include_file(path: &Path, stack: &mut Vec<Token>, env: &mut Env) -> Result<bool, JoyError> {
+    let io_error = |e: io::Error| JoyError::new(ErrorKind::Io, "include", format!("cannot read {}: {}", path.display(), e));
+    let path = fs::canonicalize(path).map_err(io_error)?;
+    if env.loaded.contains(&path) {
+        return Ok(false);
+    }
+    if env.loading.contains(&path) {
+        let chain: Vec<String> = env.loading.iter().chain([&path])
+            .map(|p| p.display().to_string())
+            .collect();
+        return Err(JoyError::new(ErrorKind::Value, "include", format!("include cycle: {}", chain.join(" -> "))));
+    }
+    let source = fs::read_to_string(&path).map_err(io_error)?;
+    env.loading.push(path.clone());
+    let depth = env.blocks.len();
+    let mut result = logical_lines(&source).iter()
+        .map(|(_, line)| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .try_fold(false, |quit, line| if quit { Ok(true) } else { run_line(line, stack, env) });
+    if env.blocks.len() > depth {
+        env.blocks.truncate(depth);
+        if result.is_ok() {
+            result = Err(JoyError::new(ErrorKind::Value, "include", format!("{}: `HIDE` or `MODULE` without `END`", path.display())));
         }
+    }
+    if env.test.take().is_some() && result.is_ok() {
+        result = Err(JoyError::new(ErrorKind::Value, "include", format!("{}: `TEST` without `END`", path.display())));
+    }
+    env.loading.pop();
+    if result.is_ok() {
+        env.loaded.insert(path);
+    }
+    result
+}
 
-        if let Ok(("", (sym, defn))) = definition(&input.trim()) {
-            programs.insert(sym, defn);
-            continue;
+/// Finds `name.joy` in the directories of `JOYPATH`, or in the current
+/// directory when it is not set.
+fn find_library(name: &str) -> Option<PathBuf> {
+    let dirs: Vec<PathBuf> = match std::env::var_os("JOYPATH") {
+        Some(path) => std::env::split_paths(&path).collect(),
+        None => vec![PathBuf::from(".")]
+    };
+    dirs.into_iter()
+        .map(|dir| dir.join(format!("{}.joy", name)))
+        .find(|file| file.is_file())
+}
+
+/// Declared stack effects of the builtins, inputs and outputs bottom to top.
+/// Type names are `int`, `float`, `num`, `bool`, `char`, `string`, `symbol`,
+/// `list` and `file`; other names are type variables, and `[p]` is a program.
+/// Effects with `...` depend on their programs, so the checker either knows
+/// the word or gives up. Keep in sync with `BUILTINS`.
+const SIGNATURES: &[(&str, &str)] = &[
+    ("+", "(num num -- num)"), ("-", "(num num -- num)"), ("*", "(num num -- num)"),
+    ("=", "(a b -- bool)"), ("!=", "(a b -- bool)"), ("<", "(a b -- bool)"), (">", "(a b -- bool)"),
+    ("<=", "(a b -- bool)"), (">=", "(a b -- bool)"), ("compare", "(a b -- int)"),
+    ("and", "(bool bool -- bool)"), ("or", "(bool bool -- bool)"), ("xor", "(bool bool -- bool)"),
+    ("not", "(bool -- bool)"),
+    ("bitand", "(int int -- int)"), ("bitor", "(int int -- int)"), ("bitxor", "(int int -- int)"),
+    ("bitnot", "(int -- int)"), ("shl", "(int int -- int)"), ("shr", "(int int -- int)"),
+    (">hex", "(int -- string)"), (">oct", "(int -- string)"), (">bin", "(int -- string)"),
+    ("integer", "(a -- bool)"), ("float", "(a -- bool)"), ("string", "(a -- bool)"), ("char", "(a -- bool)"),
+    ("logical", "(a -- bool)"), ("list", "(a -- bool)"), ("file", "(a -- bool)"), ("set", "(a -- bool)"),
+    ("leaf", "(a -- bool)"), ("user", "(a -- bool)"), ("sametype", "(a b -- bool)"), ("typeof", "(a -- symbol)"),
+    ("strtol", "(string int -- int)"), ("strtod", "(string -- float)"),
+    ("string->list", "(string -- list)"), ("list->string", "(list -- string)"),
+    ("upper", "(a -- a)"), ("lower", "(a -- a)"), ("trim", "(string -- string)"),
+    ("split-on", "(string sep -- list)"), ("join", "(list sep -- string)"), ("index-of", "(string s -- int)"),
+    ("substring", "(string int int -- string)"), ("replace", "(string string string -- string)"),
+    ("starts-with", "(string string -- bool)"), ("ends-with", "(string string -- bool)"),
+    ("format", "(int char int int -- string)"), ("formatf", "(num char int int -- string)"),
+    ("put", "(a --)"), ("putln", "(a --)"), (".", "(a --)"), ("putch", "(char --)"), ("putchars", "(string --)"),
+    ("get", "(-- a)"),
+    ("stdin", "(-- file)"), ("stdout", "(-- file)"), ("stderr", "(-- file)"),
+    ("fopen", "(string string -- file)"), ("fclose", "(file --)"), ("fflush", "(file -- file)"),
+    ("feof", "(file -- file bool)"), ("ferror", "(file -- file bool)"),
+    ("fgetch", "(file -- file c)"), ("fgets", "(file -- file string)"), ("fread", "(file int -- file list)"),
+    ("fwrite", "(file list -- file)"), ("fputs", "(file string -- file)"), ("fputch", "(file char -- file)"),
+    ("fseek", "(file int int -- file bool)"), ("ftell", "(file -- file int)"),
+    ("fremove", "(string -- bool)"), ("frename", "(string string -- bool)"),
+    ("argc", "(-- int)"), ("argv", "(-- list)"), ("getenv", "(string -- string)"), ("system", "(string -- int)"),
+    ("time", "(-- int)"), ("clock", "(-- int)"), ("localtime", "(int -- list)"), ("gmtime", "(int -- list)"),
+    ("mktime", "(list -- int)"), ("strftime", "(list string -- string)"), ("rand", "(-- int)"), ("srand", "(int --)"),
+    ("intern", "(string -- symbol)"), ("name", "(a -- string)"), ("body", "(symbol -- list)"),
+    ("defined?", "(a -- bool)"), ("define", "(symbol [p] --)"),
+    ("id", "(--)"), ("stack", "(-- list)"), ("unstack", "(list -- ...)"), ("newstack", "(... --)"),
+    ("infra", "(list [p] -- list)"),
+    ("rollup", "(x y z -- z x y)"), ("rolldown", "(x y z -- y z x)"), ("rotate", "(x y z -- z y x)"),
+    ("rollupd", "(x y z w -- z x y w)"), ("rolldownd", "(x y z w -- y z x w)"), ("over", "(x y -- x y x)"),
+    ("pick", "(... int -- ... x)"), ("dupd", "(x y -- x x y)"), ("popd", "(x y -- y)"), ("swapd", "(x y z -- y x z)"),
+    ("pop", "(x --)"), ("dup", "(x -- x x)"), ("swap", "(x y -- y x)"), ("size", "(list -- list int)"),
+    ("first", "(list -- x)"), ("rest", "(list -- list)"), ("uncons", "(list -- x list)"),
+    ("unswons", "(list -- list x)"), ("cons", "(x list -- list)"), ("swons", "(list x -- list)"),
+    ("concat", "(list list -- list)"), ("null", "(x -- bool)"), ("small", "(x -- bool)"),
+    ("i", "([p] -- ...)"), ("ifte", "([b] [t] [e] -- ...)"), ("x", "([p] -- ... [p])"), ("app1", "([p] -- ...)"),
+    ("dip", "(x [p] -- ... x)"), ("dipd", "(x y [p] -- ... x y)"), ("dipdd", "(x y z [p] -- ... x y z)"),
+    ("app11", "(x y [p] -- r)"), ("app12", "(x y z [p] -- r s)"),
+    ("app2", "(x y [p] -- r s)"), ("app3", "(x y z [p] -- r s t)"), ("app4", "(x y z w [p] -- r s t u)"),
+    ("unary2", "(x y [p] -- r s)"), ("unary3", "(x y z [p] -- r s t)"), ("unary4", "(x y z w [p] -- r s t u)"),
+    ("cleave", "(x [p] [q] -- r s)"), ("bi", "(x [p] [q] -- r s)"), ("tri", "(x [p] [q] [r] -- r s t)"),
+    ("map", "(list [p] -- list)"), ("filter", "(list [p] -- list)"), ("split", "(list [p] -- list list)"),
+    ("step", "(list [p] -- ...)"), ("treestep", "(tree [p] -- ...)"), ("treerec", "(tree [o] [c] -- ...)"),
+    ("treegenrec", "(tree [o1] [o2] [c] -- ...)"), ("construct", "([p] [[q] ...] -- ...)"),
+    ("include", "(string -- ...)"), ("use", "(-- ...)"),
+    ("try", "([p] [handler] -- ...)"), ("throw", "(x -- ...)"), ("abort", "(-- ...)"), ("quit", "(-- ...)"),
+    ("assert", "(bool --)"), ("assert-eq", "(x y --)"), ("assert-stack", "(... list -- ...)")
+];
+
+fn signature(sym: &str) -> Option<&'static str> {
+    SIGNATURES.iter().find(|(name, _)| *name == sym).map(|(_, sig)| *sig)
+}
+
+/// What each builtin does, with examples in the docstring form
+/// `==> program  ⇒  stack`. Keep in sync with `BUILTINS`.
+const BUILTIN_DOCS: &[(&str, &str)] = &[
+    ("+", "Adds two numbers.\n==> 2 3 +  ⇒  5"),
+    ("-", "Subtracts the top number from the one below.\n==> 5 3 -  ⇒  2"),
+    ("*", "Multiplies two numbers.\n==> 4 5 *  ⇒  20"),
+    ("=", "Whether two values are equal.\n==> 1 1 =  ⇒  true"),
+    ("!=", "Whether two values differ.\n==> 1 2 !=  ⇒  true"),
+    ("<", "Whether the lower value is smaller, in the order of `compare`.\n==> 1 2 <  ⇒  true"),
+    (">", "Whether the lower value is larger.\n==> 1 2 >  ⇒  false"),
+    ("<=", "Whether the lower value is not larger.\n==> 2 2 <=  ⇒  true"),
+    (">=", "Whether the lower value is not smaller.\n==> 1 2 >=  ⇒  false"),
+    ("compare", "-1, 0 or 1 as the lower value is smaller than, equal to or larger than the top one. All values are ordered, by type first.\n==> 1 2 compare  ⇒  -1\n==> \"b\" \"a\" compare  ⇒  1"),
+    ("and", "Logical and.\n==> true false and  ⇒  false"),
+    ("or", "Logical or.\n==> true false or  ⇒  true"),
+    ("xor", "Logical exclusive or.\n==> true true xor  ⇒  false"),
+    ("not", "Logical negation.\n==> false not  ⇒  true"),
+    ("bitand", "Bitwise and of two integers.\n==> 12 10 bitand  ⇒  8"),
+    ("bitor", "Bitwise or of two integers.\n==> 12 10 bitor  ⇒  14"),
+    ("bitxor", "Bitwise exclusive or of two integers.\n==> 12 10 bitxor  ⇒  6"),
+    ("bitnot", "Flips every bit of an integer.\n==> 0 bitnot  ⇒  -1"),
+    ("shl", "Shifts an integer left by 0 to 63 bits.\n==> 1 4 shl  ⇒  16"),
+    ("shr", "Shifts an integer right by 0 to 63 bits, keeping its sign.\n==> -16 2 shr  ⇒  -4"),
+    (">hex", "Writes an integer in hexadecimal.\n==> 255 >hex  ⇒  \"0xFF\""),
+    (">oct", "Writes an integer in octal.\n==> 8 >oct  ⇒  \"0o10\""),
+    (">bin", "Writes an integer in binary.\n==> 5 >bin  ⇒  \"0b101\""),
+    ("integer", "Whether a value is an integer.\n==> 1 integer  ⇒  true"),
+    ("float", "Whether a value is a float.\n==> 1.5 float  ⇒  true"),
+    ("string", "Whether a value is a string.\n==> \"a\" string  ⇒  true"),
+    ("char", "Whether a value is a character.\n==> 'a char  ⇒  true"),
+    ("logical", "Whether a value is a boolean.\n==> true logical  ⇒  true"),
+    ("list", "Whether a value is a list.\n==> [] list  ⇒  true"),
+    ("file", "Whether a value is a file.\n==> stdin file  ⇒  true"),
+    ("set", "Whether a value is a set. There is no set type yet, so this is always false.\n==> [] set  ⇒  false"),
+    ("leaf", "Whether a value is a leaf of a tree, that is anything but a list.\n==> 1 leaf  ⇒  true"),
+    ("user", "Whether a symbol names a user definition.\n==> [sum] first user  ⇒  true"),
+    ("sametype", "Whether two values have the same type.\n==> 1 2 sametype  ⇒  true"),
+    ("typeof", "The name of the type of a value, as a symbol.\n==> 1 typeof  ⇒  integer"),
+    ("strtol", "Parses an integer in a base from 2 to 36, or in the base its prefix gives when the base is 0.\n==> \"ff\" 16 strtol  ⇒  255"),
+    ("strtod", "Parses a float.\n==> \"2.5\" strtod  ⇒  2.5"),
+    ("string->list", "The characters of a string.\n==> \"ab\" string->list  ⇒  ['a 'b]"),
+    ("list->string", "Joins a list of characters into a string.\n==> ['a 'b] list->string  ⇒  \"ab\""),
+    ("upper", "A string or character in upper case.\n==> \"abc\" upper  ⇒  \"ABC\"\n==> 'a b' upper  ⇒  \"A B\""),
+    ("lower", "A string or character in lower case.\n==> 'A lower  ⇒  'a"),
+    ("trim", "A string without its leading and trailing whitespace.\n==> \"  a \" trim  ⇒  \"a\""),
+    ("split-on", "Splits a string on a string or character separator.\n==> \"a,b\" ', split-on  ⇒  [\"a\" \"b\"]"),
+    ("join", "Joins a list of strings with a separator.\n==> [\"a\" \"b\"] \"-\" join  ⇒  \"a-b\""),
+    ("index-of", "Where a string or character first occurs in a string, or -1.\n==> \"hello\" 'l index-of  ⇒  2"),
+    ("substring", "Part of a string, given its start and length in characters.\n==> \"hello\" 1 3 substring  ⇒  \"ell\""),
+    ("replace", "Replaces every occurrence of a string in another.\n==> \"aXb\" \"X\" \"-\" replace  ⇒  \"a-b\""),
+    ("starts-with", "Whether a string starts with another.\n==> \"joy\" \"j\" starts-with  ⇒  true"),
+    ("ends-with", "Whether a string ends with another.\n==> \"joy\" \"oy\" ends-with  ⇒  true"),
+    ("format", "Formats an integer like printf, given a mode of 'd, 'i, 'o, 'x or 'X, a width and a precision.\n==> 42 'd 5 0 format  ⇒  \"   42\""),
+    ("formatf", "Formats a float like printf, given a mode of 'f, 'F, 'e, 'E, 'g or 'G, a width and a precision.\n==> 3.14159 'f 0 2 formatf  ⇒  \"3.14\"\n==> 999999.5 'g 0 6 formatf  ⇒  \"1e+06\""),
+    ("put", "Writes a value in Joy syntax, then a space."),
+    ("putln", "Writes a value in Joy syntax, then a newline."),
+    (".", "Writes a value in Joy syntax, then a newline."),
+    ("putch", "Writes a character."),
+    ("putchars", "Writes the characters of a string."),
+    ("get", "Reads the next value from the input."),
+    ("stdin", "The standard input file."),
+    ("stdout", "The standard output file."),
+    ("stderr", "The standard error file."),
+    ("fopen", "Opens a file with a mode of \"r\", \"w\" or \"a\"."),
+    ("fclose", "Closes a file."),
+    ("fflush", "Writes out what is buffered for a file."),
+    ("feof", "Whether a file has reached its end."),
+    ("ferror", "Whether a file has had an error."),
+    ("fgetch", "Reads a character from a file, or -1 at its end."),
+    ("fgets", "Reads a line from a file, with its newline."),
+    ("fread", "Reads up to a number of bytes from a file."),
+    ("fwrite", "Writes a list of bytes to a file."),
+    ("fputs", "Writes a string to a file."),
+    ("fputch", "Writes a character to a file."),
+    ("fseek", "Moves in a file to a position, from the start, the current position or the end as the last number is 0, 1 or 2. Leaves whether that failed."),
+    ("ftell", "The position in a file."),
+    ("fremove", "Deletes a file, leaving whether that worked."),
+    ("frename", "Renames a file, leaving whether that worked."),
+    ("argc", "The number of command line arguments, the program name included."),
+    ("argv", "The command line arguments, starting with the program name."),
+    ("getenv", "The value of an environment variable, or \"\"."),
+    ("system", "Runs a shell command and leaves its exit status."),
+    ("time", "Seconds since the epoch."),
+    ("clock", "CPU time used, in hundredths of a second."),
+    ("localtime", "Splits a time into the list [year month day hour minute second dst yday wday], in the local time zone."),
+    ("gmtime", "Splits a time into the list [year month day hour minute second dst yday wday], in UTC.\n==> 0 gmtime  ⇒  [1970 1 1 0 0 0 false 0 4]"),
+    ("mktime", "The time of a list from `localtime`."),
+    ("strftime", "Formats a list from `localtime` or `gmtime` like C's strftime.\n==> 0 gmtime \"%Y\" strftime  ⇒  \"1970\""),
+    ("rand", "A pseudo-random integer."),
+    ("srand", "Seeds the `rand` generator."),
+    ("intern", "The symbol a string spells.\n==> \"dup\" intern  ⇒  dup"),
+    ("name", "The name of a symbol, or of the type of another value.\n==> [dup] first name  ⇒  \"dup\""),
+    ("body", "The body of a user definition.\n==> [fold] first body  ⇒  [swapd step]"),
+    ("defined?", "Whether a symbol names a builtin or a user definition.\n==> [dup] first defined?  ⇒  true"),
+    ("define", "Defines a symbol as a program.\n==> [twice] first [2 *] define 4 twice  ⇒  8"),
+    ("id", "Does nothing.\n==> 1 id  ⇒  1"),
+    ("stack", "Pushes a list of the stack, top first.\n==> 1 2 stack  ⇒  1 2 [2 1]"),
+    ("unstack", "Replaces the stack with a list, its first element on top.\n==> 1 [2 3] unstack  ⇒  3 2"),
+    ("newstack", "Empties the stack."),
+    ("infra", "Runs a program with a list as the stack, and leaves the resulting stack as a list.\n==> [1 2] [+] infra  ⇒  [3]"),
+    ("rollup", "Moves the top value under the next two.\n==> 1 2 3 rollup  ⇒  3 1 2"),
+    ("rolldown", "Moves the third value to the top.\n==> 1 2 3 rolldown  ⇒  2 3 1"),
+    ("rotate", "Swaps the first and third values.\n==> 1 2 3 rotate  ⇒  3 2 1"),
+    ("rollupd", "`rollup` under the top value.\n==> 1 2 3 4 rollupd  ⇒  3 1 2 4"),
+    ("rolldownd", "`rolldown` under the top value.\n==> 1 2 3 4 rolldownd  ⇒  2 3 1 4"),
+    ("over", "Copies the second value to the top.\n==> 1 2 over  ⇒  1 2 1"),
+    ("pick", "Copies the value as deep as the number on top, 0 being the top.\n==> 1 2 3 2 pick  ⇒  1 2 3 1"),
+    ("dupd", "`dup` under the top value.\n==> 1 2 dupd  ⇒  1 1 2"),
+    ("popd", "`pop` under the top value.\n==> 1 2 popd  ⇒  2"),
+    ("swapd", "`swap` under the top value.\n==> 1 2 3 swapd  ⇒  2 1 3"),
+    ("pop", "Drops the top value.\n==> 1 2 pop  ⇒  1"),
+    ("dup", "Copies the top value.\n==> 1 dup  ⇒  1 1"),
+    ("swap", "Swaps the top two values.\n==> 1 2 swap  ⇒  2 1"),
+    ("size", "The length of a list, leaving the list.\n==> [1 2 3] size  ⇒  [1 2 3] 3"),
+    ("first", "The first element of a list.\n==> [1 2 3] first  ⇒  1"),
+    ("rest", "A list without its first element.\n==> [1 2 3] rest  ⇒  [2 3]"),
+    ("uncons", "The first element of a list and the rest.\n==> [1 2 3] uncons  ⇒  1 [2 3]"),
+    ("unswons", "The rest of a list and its first element.\n==> [1 2 3] unswons  ⇒  [2 3] 1"),
+    ("cons", "Puts a value in front of a list.\n==> 1 [2 3] cons  ⇒  [1 2 3]"),
+    ("swons", "Puts the top value in front of the list below it.\n==> [2 3] 1 swons  ⇒  [1 2 3]"),
+    ("concat", "Joins two lists.\n==> [1 2] [3] concat  ⇒  [1 2 3]"),
+    ("null", "Whether a list or string is empty, or a number is zero.\n==> [] null  ⇒  true"),
+    ("small", "Whether a list or string has less than two elements, or a number is 0 or 1.\n==> [1] small  ⇒  true"),
+    ("i", "Runs a program.\n==> [1 2 +] i  ⇒  3"),
+    ("ifte", "Runs the then or else program as the if program, run on a copy of the stack, leaves true or false.\n==> 5 [0 >] [\"pos\"] [\"neg\"] ifte  ⇒  5 \"pos\""),
+    ("x", "Runs a program, leaving it on the stack under what it works on.\n==> [pop 1] x  ⇒  1"),
+    ("app1", "Runs a program, like `i`.\n==> 2 [dup *] app1  ⇒  4"),
+    ("dip", "Runs a program under the top value.\n==> 1 2 [10 +] dip  ⇒  11 2"),
+    ("dipd", "Runs a program under the top two values.\n==> 1 2 3 [10 +] dipd  ⇒  11 2 3"),
+    ("dipdd", "Runs a program under the top three values.\n==> 1 2 3 4 [10 +] dipdd  ⇒  11 2 3 4"),
+    ("app11", "Replaces two values by what a program leaves on top when run on them.\n==> 1 2 [+] app11  ⇒  3"),
+    ("app12", "Runs a program on the value below two others with each of them, and keeps the results.\n==> 10 1 2 [+] app12  ⇒  11 12"),
+    ("app2", "Runs a program on each of two values.\n==> 1 2 [dup *] app2  ⇒  1 4"),
+    ("app3", "Runs a program on each of three values.\n==> 1 2 3 [dup *] app3  ⇒  1 4 9"),
+    ("app4", "Runs a program on each of four values.\n==> 1 2 3 4 [dup *] app4  ⇒  1 4 9 16"),
+    ("unary2", "`app2` by its Joy name.\n==> 1 2 [dup *] unary2  ⇒  1 4"),
+    ("unary3", "`app3` by its Joy name.\n==> 1 2 3 [dup *] unary3  ⇒  1 4 9"),
+    ("unary4", "`app4` by its Joy name.\n==> 1 2 3 4 [dup *] unary4  ⇒  1 4 9 16"),
+    ("cleave", "Runs two programs on the same value.\n==> 3 [dup *] [1 +] cleave  ⇒  9 4\n==> 1 2 [+] [-] cleave  ⇒  1 3 -1"),
+    ("bi", "`cleave` by its Factor name.\n==> 3 [dup *] [1 +] bi  ⇒  9 4\n==> 1 2 [+] [-] bi  ⇒  1 3 -1"),
+    ("tri", "Runs three programs on the same value.\n==> 3 [1 +] [2 *] [dup *] tri  ⇒  4 6 9\n==> 1 2 [+] [-] [*] tri  ⇒  1 3 -1 2"),
+    ("map", "Runs a program on each element of a list and collects the results.\n==> [1 2 3] [dup *] map  ⇒  [1 4 9]"),
+    ("filter", "The elements of a list for which a test leaves true.\n==> [1 2 3 4] [2 >] filter  ⇒  [3 4]"),
+    ("split", "The elements of a list for which a test leaves true, and those for which it leaves false.\n==> [1 2 3 4] [2 >] split  ⇒  [3 4] [1 2]"),
+    ("step", "Pushes each element of a list in turn and runs a program.\n==> 0 [1 2 3] [+] step  ⇒  6"),
+    ("treestep", "Pushes each leaf of a tree in turn and runs a program.\n==> 0 [1 [2 3]] [+] treestep  ⇒  6"),
+    ("treerec", "Runs O on a leaf; on a list, runs C with the whole `treerec` quoted on top.\n==> [1 [2 3]] [dup *] [map] treerec  ⇒  [1 [4 9]]"),
+    ("treegenrec", "Runs O1 on a leaf; on a list, runs O2 then C with the whole `treegenrec` quoted on top.\n==> [1 [2 3]] [dup *] [] [map] treegenrec  ⇒  [1 [4 9]]"),
+    ("construct", "Runs a program on a copy of the stack, then each program of a list on copies of the result, and pushes what they leave on top.\n==> 3 [1 +] [[dup *] [2 *]] construct  ⇒  3 16 8"),
+    ("include", "Runs a Joy file, once, as in `include \"lib.joy\"`."),
+    ("use", "Runs the file `name.joy` found on JOYPATH, once, as in `use name`."),
+    ("try", "Runs a program; if it fails, restores the stack, pushes the error as [kind \"message\" value] and runs the handler.\n==> [undefined-word] [first] try  ⇒  undefined"),
+    ("throw", "Fails with a value, or rethrows an error list caught by `try`."),
+    ("abort", "Fails in a way `try` does not catch."),
+    ("quit", "Leaves the interpreter."),
+    ("assert", "Fails with an assertion error unless the boolean is true.\n==> 5 2 1 + 3 = assert  ⇒  5"),
+    ("assert-eq", "Fails with an assertion error unless the value below equals the top one.\n==> 5 2 1 + 3 assert-eq  ⇒  5"),
+    ("assert-stack", "Fails with an assertion error unless the stack below holds exactly the items of the list, bottom first.\n==> 1 2 [1 2] assert-stack  ⇒  1 2")
+];
+
+fn builtin_doc(sym: &str) -> Option<&'static str> {
+    BUILTIN_DOCS.iter().find(|(name, _)| *name == sym).map(|(_, doc)| *doc)
+}
+
+/// The types the checker tells apart. `Num` is an int or a float, and `Any`
+/// is not known yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty { Any, Num, Int, Float, Bool, Char, Str, Symbol, List, File }
+
+impl Ty {
+    fn name(self) -> &'static str {
+        match self {
+            Ty::Any => "any",
+            Ty::Num => "num",
+            Ty::Int => "int",
+            Ty::Float => "float",
+            Ty::Bool => "bool",
+            Ty::Char => "char",
+            Ty::Str => "string",
+            Ty::Symbol => "symbol",
+            Ty::List => "list",
+            Ty::File => "file"
         }
-        let mut new_input: &str = &input.trim().as_ref();
-        while !quit && !new_input.is_empty() {
-            let parser = tuple((opt(whitespace), token));
-            match parser(&new_input) {
-                Ok((left, tok)) => {
-                    quit = exec(vec![tok.1], &mut stack, &programs);
-                    new_input = left;
-                },
-                _ => {
-                    break;
-                }
+    }
+
+    /// The type named in a signature, or `None` for a type variable.
+    fn parse(s: &str) -> Option<Ty> {
+        if s.starts_with('[') {
+            return Some(Ty::List);
+        }
+        [Ty::Any, Ty::Num, Ty::Int, Ty::Float, Ty::Bool, Ty::Char, Ty::Str, Ty::Symbol, Ty::List, Ty::File]
+            .iter().copied()
+            .find(|t| t.name() == s)
+    }
+
+    fn of(t: &Token) -> Ty {
+        match t {
+            Token::Number(_) => Ty::Int,
+            Token::Boolean(_) => Ty::Bool,
+            Token::Decimal(_) => Ty::Float,
+            Token::Char(_) => Ty::Char,
+            Token::String(_) => Ty::Str,
+            Token::Symbol(_) => Ty::Symbol,
+            Token::Quotation(_) => Ty::List,
+            Token::File(_) => Ty::File
+        }
+    }
+
+    /// The type that is both, or `None` if they conflict.
+    fn meet(self, other: Ty) -> Option<Ty> {
+        match (self, other) {
+            (a, b) if a == b => Some(a),
+            (Ty::Any, t) | (t, Ty::Any) => Some(t),
+            (Ty::Num, t) | (t, Ty::Num) if t == Ty::Int || t == Ty::Float => Some(t),
+            _ => None
+        }
+    }
+
+    /// The type that covers both.
+    fn join(self, other: Ty) -> Ty {
+        let numeric = |t| matches!(t, Ty::Num | Ty::Int | Ty::Float);
+        match (self, other) {
+            (a, b) if a == b => a,
+            (a, b) if numeric(a) && numeric(b) => Ty::Num,
+            _ => Ty::Any
+        }
+    }
+}
+
+/// Splits a signature such as `(int x -- x)` into its inputs and outputs,
+/// or `None` if it is malformed or has a `...` part.
+fn parse_effect(sig: &str) -> Option<(Vec<&str>, Vec<&str>)> {
+    let inner = sig.trim().strip_prefix('(')?.strip_suffix(')')?;
+    let (ins, outs) = inner.split_once("--")?;
+    let ins: Vec<&str> = ins.split_whitespace().collect();
+    let outs: Vec<&str> = outs.split_whitespace().collect();
+    if ins.iter().chain(&outs).any(|s| s.contains("...")) {
+        return None;
+    }
+    Some((ins, outs))
+}
+
+/// The checker's picture of a stack: values by id, and the values a
+/// definition takes from below its own stack, topmost first.
+#[derive(Clone, Default)]
+struct Shape {
+    stack: Vec<usize>,
+    inputs: Vec<usize>
+}
+
+/// Runs programs over types instead of values to find their stack effect.
+/// It gives up (`None`) where the effect depends on run time values, like
+/// recursion or programs that are computed, and records the conflicts that
+/// would fail on every run.
+struct Checker<'a> {
+    programs: &'a HashMap<String, Vec<Token>>,
+    /// Declared effects, which stand in for the bodies of their words.
+    effects: &'a HashMap<String, String>,
+    types: Vec<Ty>,
+    /// The program a value is known to be, for the combinators.
+    quotes: Vec<Option<Vec<Token>>>,
+    /// User words being expanded, so that recursion stops the checker.
+    active: Vec<String>,
+    errors: Vec<JoyError>,
+    /// Whether the stack starts empty, making a pop past its bottom an
+    /// underflow rather than another input.
+    top_level: bool
+}
+
+impl<'a> Checker<'a> {
+    fn new(env: &'a Env, top_level: bool) -> Checker<'a> {
+        Checker {
+            programs: &env.programs,
+            effects: &env.effects,
+            types: Vec::new(),
+            quotes: Vec::new(),
+            active: Vec::new(),
+            errors: Vec::new(),
+            top_level
+        }
+    }
+
+    fn value(&mut self, ty: Ty, quote: Option<Vec<Token>>) -> usize {
+        self.types.push(ty);
+        self.quotes.push(quote);
+        self.types.len() - 1
+    }
+
+    fn pop(&mut self, shape: &mut Shape, want: Ty, sym: &str) -> Option<usize> {
+        let id = match shape.stack.pop() {
+            Some(id) => id,
+            None if self.top_level => {
+                self.errors.push(JoyError::new(ErrorKind::Underflow, sym, format!("`{}` underflows the stack", sym)));
+                return None;
+            },
+            None => {
+                let id = self.value(Ty::Any, None);
+                shape.inputs.push(id);
+                id
+            }
+        };
+        match self.types[id].meet(want) {
+            Some(ty) => {
+                self.types[id] = ty;
+                Some(id)
+            },
+            None => {
+                let message = format!("`{}` needs {} but gets {}", sym, want.name(), self.types[id].name());
+                self.errors.push(JoyError::new(ErrorKind::Type, sym, message));
+                None
+            }
+        }
+    }
+
+    /// Pops a program whose body is known.
+    fn program(&mut self, shape: &mut Shape, sym: &str) -> Option<Vec<Token>> {
+        let id = self.pop(shape, Ty::List, sym)?;
+        self.quotes[id].clone()
+    }
+
+    /// Moves the inputs that `inner`, a copy of `shape`, found below the
+    /// bottom of the stack to `shape` as well.
+    fn adopt_inputs(&mut self, shape: &mut Shape, inner: &Shape) {
+        for &id in &inner.inputs[shape.inputs.len()..] {
+            shape.stack.insert(0, id);
+            shape.inputs.push(id);
+        }
+    }
+
+    /// Runs `p` on a copy of `shape`, with `arg` pushed, and returns the
+    /// value it leaves on top, as `exec_top` does.
+    fn run_top(&mut self, p: &[Token], shape: &mut Shape, arg: Option<usize>, want: Ty, sym: &str) -> Option<usize> {
+        let mut inner = shape.clone();
+        inner.stack.extend(arg);
+        let top = self.run(p, &mut inner).and_then(|_| self.pop(&mut inner, want, sym));
+        self.adopt_inputs(shape, &inner);
+        top
+    }
+
+    /// Applies a declared signature to `shape`.
+    fn apply(&mut self, sig: &str, shape: &mut Shape, sym: &str) -> Option<()> {
+        let (ins, outs) = parse_effect(sig)?;
+        let mut vars = HashMap::new();
+        for name in ins.into_iter().rev() {
+            let id = self.pop(shape, Ty::parse(name).unwrap_or(Ty::Any), sym)?;
+            if Ty::parse(name).is_none() {
+                vars.insert(name, id);
             }
         }
-        println!("{:?}", stack);
+        for name in outs {
+            let id = match (Ty::parse(name), vars.get(name)) {
+                (None, Some(&id)) => id,
+                (ty, _) => self.value(ty.unwrap_or(Ty::Any), None)
+            };
+            shape.stack.push(id);
+        }
+        Some(())
     }
 
+    /// Joins the shapes left by the two branches of an `ifte`, which both
+    /// started with `before` inputs, or `None` if their depths differ.
+    fn merge(&mut self, a: Shape, b: Shape, before: usize) -> Option<Shape> {
+        let (long, mut short) = if a.inputs.len() >= b.inputs.len() { (a, b) } else { (b, a) };
+        let mut same = HashMap::new();
+        for i in before..long.inputs.len() {
+            match short.inputs.get(i) {
+                Some(&id) => {
+                    same.insert(id, long.inputs[i]);
+                    self.types[long.inputs[i]] = self.types[long.inputs[i]].join(self.types[id]);
+                },
+                None => short.stack.insert(0, long.inputs[i])
+            }
+        }
+        if long.stack.len() != short.stack.len() {
+            return None;
+        }
+        let stack = long.stack.iter().zip(&short.stack).map(|(&x, y)| {
+            let y = same.get(y).copied().unwrap_or(*y);
+            if x == y { x } else { self.value(self.types[x].join(self.types[y]), None) }
+        }).collect();
+        Some(Shape { stack, inputs: long.inputs })
+    }
+
+    fn run(&mut self, body: &[Token], shape: &mut Shape) -> Option<()> {
+        let mut toks = body.iter().peekable();
+        while let Some(tok) = toks.next() {
+            let sym = match tok {
+                Token::Symbol(s) => s.as_str(),
+                Token::Quotation(q) => {
+                    let id = self.value(Ty::List, Some(q.clone()));
+                    shape.stack.push(id);
+                    continue;
+                },
+                t => {
+                    let id = self.value(Ty::of(t), None);
+                    shape.stack.push(id);
+                    continue;
+                }
+            };
+            match sym {
+                "i" | "app1" => {
+                    let p = self.program(shape, sym)?;
+                    self.run(&p, shape)?;
+                },
+                "x" => {
+                    let id = self.pop(shape, Ty::List, sym)?;
+                    shape.stack.push(id);
+                    let p = self.quotes[id].clone()?;
+                    self.run(&p, shape)?;
+                },
+                "dip" | "dipd" | "dipdd" => {
+                    let p = self.program(shape, sym)?;
+                    let mut saved = Vec::new();
+                    for _ in 0..sym_arity(sym) {
+                        saved.insert(0, self.pop(shape, Ty::Any, sym)?);
+                    }
+                    self.run(&p, shape)?;
+                    shape.stack.extend(saved);
+                },
+                "ifte" => {
+                    let else_p = self.program(shape, sym)?;
+                    let then_p = self.program(shape, sym)?;
+                    let if_p = self.program(shape, sym)?;
+                    self.run_top(&if_p, shape, None, Ty::Bool, sym)?;
+                    let before = shape.inputs.len();
+                    let mut then_shape = shape.clone();
+                    let mut else_shape = shape.clone();
+                    let then_ok = self.run(&then_p, &mut then_shape).is_some();
+                    let else_ok = self.run(&else_p, &mut else_shape).is_some();
+                    *shape = match (then_ok, else_ok) {
+                        (true, true) => self.merge(then_shape, else_shape, before)?,
+                        // the other branch recurses or cannot be followed
+                        (true, false) => then_shape,
+                        (false, true) => else_shape,
+                        (false, false) => return None
+                    };
+                },
+                "map" | "filter" | "split" => {
+                    let p = self.pop(shape, Ty::List, sym)?;
+                    self.pop(shape, Ty::List, sym)?;
+                    if let Some(p) = self.quotes[p].clone() {
+                        let x = self.value(Ty::Any, None);
+                        let want = if sym == "map" { Ty::Any } else { Ty::Bool };
+                        self.run_top(&p, shape, Some(x), want, sym);
+                    }
+                    for _ in 0..if sym == "split" { 2 } else { 1 } {
+                        let id = self.value(Ty::List, None);
+                        shape.stack.push(id);
+                    }
+                },
+                "step" => {
+                    let p = self.program(shape, sym)?;
+                    self.pop(shape, Ty::List, sym)?;
+                    let x = self.value(Ty::Any, None);
+                    shape.stack.push(x);
+                    self.run(&p, shape)?;
+                },
+                "use" => {
+                    toks.next();
+                },
+                "include" if matches!(toks.peek(), Some(Token::String(_))) => {
+                    toks.next();
+                },
+                _ => match (signature(sym).or_else(|| self.effects.get(sym).map(String::as_str)), self.programs.get(sym)) {
+                    (Some(sig), _) => self.apply(sig, shape, sym)?,
+                    (None, Some(body)) if !self.active.iter().any(|w| w == sym) => {
+                        self.active.push(sym.to_string());
+                        let result = self.run(body, shape);
+                        self.active.pop();
+                        result?;
+                    },
+                    // recursion, or a word that is not defined yet
+                    _ => return None
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Writes the effect of `shape` as a signature, naming unknown types
+    /// with letters.
+    fn describe(&self, shape: &Shape) -> String {
+        let mut letters: Vec<usize> = Vec::new();
+        let mut name = |id: usize| match self.types[id] {
+            Ty::Any => {
+                let n = letters.iter().position(|&l| l == id).unwrap_or_else(|| {
+                    letters.push(id);
+                    letters.len() - 1
+                });
+                ((b'a' + (n % 26) as u8) as char).to_string()
+            },
+            ty => ty.name().to_string()
+        };
+        let mut parts: Vec<String> = shape.inputs.iter().rev().map(|&id| name(id)).collect();
+        parts.push("--".to_string());
+        parts.extend(shape.stack.iter().map(|&id| name(id)));
+        format!("({})", parts.join(" "))
+    }
+}
+
+/// Infers the stack effect of the definition `name == body`, if it can be
+/// known before running it, and the type conflicts found on the way.
+fn infer_effect(name: &str, body: &[Token], env: &Env) -> (Option<String>, Vec<JoyError>) {
+    let mut checker = Checker::new(env, false);
+    checker.active.push(name.to_string());
+    let mut shape = Shape::default();
+    let effect = checker.run(body, &mut shape).map(|_| checker.describe(&shape));
+    (effect, checker.errors)
+}
+
+/// Whether a declared effect agrees with an inferred one: they take and
+/// leave as many values, and no types conflict.
+fn effects_agree(declared: &str, inferred: &str) -> bool {
+    match (parse_effect(declared), parse_effect(inferred)) {
+        (Some((din, dout)), Some((iin, iout))) => {
+            let agree = |d: &Vec<&str>, i: &Vec<&str>| d.len() == i.len() && d.iter().zip(i).all(|(d, i)| {
+                let ty = |s: &str| Ty::parse(s).unwrap_or(Ty::Any);
+                ty(d).meet(ty(i)).is_some()
+            });
+            agree(&din, &iin) && agree(&dout, &iout)
+        },
+        _ => true
+    }
+}
+
+/// The conflicts in a definition, and a declared effect that does not
+/// match the inferred one.
+fn check_definition(name: &str, env: &Env) -> Vec<JoyError> {
+    let body = match env.programs.get(name) {
+        Some(body) => body,
+        None => return Vec::new()
+    };
+    let (inferred, mut errors) = infer_effect(name, body, env);
+    match (env.effects.get(name), inferred) {
+        (Some(declared), _) if !declared.contains("--") => errors.push(JoyError::new(
+            ErrorKind::Value, name, format!("stack effect {} needs `--` between inputs and outputs", declared)
+        )),
+        (Some(declared), Some(inferred)) if !effects_agree(declared, &inferred) => errors.push(JoyError::new(
+            ErrorKind::Type, name, format!("declared {} but inferred {}", declared, inferred)
+        )),
+        _ => {}
+    }
+    errors
+}
+
+/// Reports the conflicts in a definition that was just made.
+fn report_definition(name: &str, env: &Env) {
+    if !env.static_check {
+        return;
+    }
+    for error in check_definition(name, env) {
+        println!("in `{}`: {}", name, error);
+    }
+}
+
+/// Checks the values a word with a declared effect takes, before it runs,
+/// or leaves, after it ran on a stack of `depth` values below its inputs.
+fn assert_effect(sym: &str, effect: &str, stack: &[Token], depth: Option<usize>) -> Result<usize, JoyError> {
+    let (ins, outs) = match parse_effect(effect) {
+        Some(parts) => parts,
+        None => return Ok(0)
+    };
+    let (below, names) = match depth {
+        None if stack.len() < ins.len() => return Err(JoyError::new(
+            ErrorKind::Underflow, sym, format!("`{}` {} needs {} values", sym, effect, ins.len())
+        )),
+        None => (stack.len() - ins.len(), ins),
+        Some(depth) if stack.len() != depth + outs.len() => return Err(JoyError::new(
+            ErrorKind::Type, sym, format!("`{}` {} left {} values", sym, effect, stack.len() as i64 - depth as i64)
+        )),
+        Some(depth) => (depth, outs)
+    };
+    for (name, value) in names.iter().zip(&stack[below..]) {
+        if matches!(Ty::parse(name), Some(ty) if ty.meet(Ty::of(value)).is_none()) {
+            return Err(JoyError::new(ErrorKind::Type, sym, format!("`{}` {} got {} for {}", sym, effect, value, name)));
+        }
+    }
+    Ok(below)
+}
+
+/// The line where `name` is defined in `source`, looking through module
+/// prefixes and hidden names.
+fn definition_line(source: &str, name: &str) -> Option<usize> {
+    let short = name.split(" #").next().unwrap_or(name);
+    let candidates = [name, short, short.rsplit('.').next().unwrap_or(short)];
+    let lines = logical_lines(source);
+    candidates.iter().find_map(|&candidate| {
+        lines.iter().find(|(_, line)| match definition(line.trim()) {
+            Ok(("", (sym, _, _))) => sym == candidate,
+            _ => false
+        }).map(|(n, _)| *n)
+    })
+}
+
+/// Checks a file without running it: its definitions and the code run at its
+/// top level, which starts with an empty stack. Prints the problems found
+/// and returns how many there were.
+fn check_file(path: &str) -> usize {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            println!("cannot read {}: {}", path, error);
+            return 1;
+        }
+    };
+    let mut env = Env::new();
+    let mut stack = Vec::new();
+    env.static_check = false;
+    load_source(PRELUDE, &mut stack, &mut env);
+    let prelude: HashSet<String> = env.programs.keys().cloned().collect();
+    env.checking = true;
+    env.loading.extend(fs::canonicalize(path));
+    let mut problems: Vec<(usize, String)> = Vec::new();
+    let lines = logical_lines(&source);
+    let code: Vec<(usize, &str)> = lines.iter()
+        .map(|(n, line)| (*n, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    for &(n, line) in &code {
+        if let Err(error) = run_line(line, &mut stack, &mut env) {
+            problems.push((n, error.to_string()));
+        }
+    }
+    if !env.blocks.is_empty() {
+        problems.push((code.last().map_or(1, |c| c.0), "`HIDE` or `MODULE` without `END`".to_string()));
+    }
+
+    let mut checker = Checker::new(&env, true);
+    let mut shape = Shape::default();
+    let mut stopped = false;
+    let mut comment = None;
+    for &(n, line) in &code {
+        let line = strip_comments(line, &mut comment).0;
+        let line = line.trim();
+        if stopped || line.is_empty() || is_block_line(line) || matches!(definition(line), Ok(("", _))) {
+            continue;
+        }
+        let toks = match expr(line) {
+            Ok((_, toks)) => toks,
+            Err(_) => continue
+        };
+        let errors = checker.errors.len();
+        stopped = checker.run(&toks, &mut shape).is_none();
+        problems.extend(checker.errors[errors..].iter().map(|e| (n, e.to_string())));
+    }
+
+    let mut names: Vec<&String> = env.programs.keys().filter(|n| !prelude.contains(*n)).collect();
+    names.sort();
+    for name in names {
+        let line = definition_line(&source, name).unwrap_or(1);
+        for error in check_definition(name, &env) {
+            problems.push((line, format!("in `{}`: {}", name, error)));
+        }
+    }
+    problems.sort_by_key(|p| p.0);
+    for (line, problem) in &problems {
+        println!("{}:{}: {}", path, line, problem);
+    }
+    problems.len()
+}
+
+/// What `joy doc` shows of a word.
+struct WordDoc {
+    name: String,
+    effect: Option<String>,
+    doc: String,
+    /// The definition of a user word.
+    definition: Option<String>
+}
+
+fn user_word_doc(name: &str, env: &Env) -> WordDoc {
+    let body = &env.programs[name];
+    let effect = env.effects.get(name).cloned().or_else(|| infer_effect(name, body, env).0);
+    WordDoc {
+        name: name.to_string(),
+        effect,
+        doc: env.docs.get(name).cloned().unwrap_or_default(),
+        definition: Some(format_definition(name, env.effects.get(name), body))
+    }
+}
+
+/// Splits a docstring into its text and its `==>` examples.
+fn doc_examples(doc: &str) -> (Vec<&str>, Vec<&str>) {
+    doc.lines().map(str::trim).partition(|line| !line.starts_with("==>"))
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// An HTML id for a word, whose name may hold any character.
+fn anchor(name: &str) -> String {
+    let mut id = String::from("w-");
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            id.push(c);
+        } else {
+            id.push_str(&format!("_{:x}", c as u32));
+        }
+    }
+    id
+}
+
+fn render_markdown(title: &str, sections: &[(String, Vec<WordDoc>)]) -> String {
+    let mut out = format!("# {}\n", title);
+    for (heading, words) in sections {
+        out.push_str(&format!("\n## {}\n", heading));
+        for word in words {
+            out.push_str(&format!("\n### `{}`", word.name));
+            if let Some(effect) = &word.effect {
+                out.push_str(&format!(" `{}`", effect));
+            }
+            out.push('\n');
+            let (text, examples) = doc_examples(&word.doc);
+            let text = text.join("\n");
+            if !text.trim().is_empty() {
+                out.push_str(&format!("\n{}\n", text.trim()));
+            }
+            if !examples.is_empty() {
+                out.push_str(&format!("\n```\n{}\n```\n", examples.join("\n")));
+            }
+            if let Some(definition) = &word.definition {
+                out.push_str(&format!("\n```joy\n{}\n```\n", definition));
+            }
+        }
+    }
+    out
+}
+
+fn render_html(title: &str, sections: &[(String, Vec<WordDoc>)]) -> String {
+    let known: HashSet<&str> = sections.iter().flat_map(|(_, words)| words.iter().map(|w| w.name.as_str())).collect();
+    let link = |name: &str| if known.contains(name) {
+        format!("<a href=\"#{}\">{}</a>", anchor(name), escape_html(name))
+    } else {
+        escape_html(name)
+    };
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n<style>\n\
+         body {{ font-family: sans-serif; max-width: 50em; margin: auto; }}\n\
+         code, pre {{ background: #f4f4f4; }}\n\
+         nav a {{ margin-right: 0.6em; }}\n\
+         </style>\n</head>\n<body>\n<h1>{0}</h1>\n",
+        escape_html(title)
+    );
+    for (heading, words) in sections {
+        let names: Vec<String> = words.iter().map(|w| link(&w.name)).collect();
+        out.push_str(&format!("<h2>{}</h2>\n<nav>{}</nav>\n", escape_html(heading), names.join(" ")));
+    }
+    for (heading, words) in sections {
+        out.push_str(&format!("<h2>{}</h2>\n", escape_html(heading)));
+        for word in words {
+            out.push_str(&format!("<h3 id=\"{}\"><code>{}</code>", anchor(&word.name), escape_html(&word.name)));
+            if let Some(effect) = &word.effect {
+                out.push_str(&format!(" <code>{}</code>", escape_html(effect)));
+            }
+            out.push_str("</h3>\n");
+            let (text, examples) = doc_examples(&word.doc);
+            let text = text.join("\n");
+            if !text.trim().is_empty() {
+                out.push_str(&format!("<p>{}</p>\n", escape_html(text.trim())));
+            }
+            if !examples.is_empty() {
+                out.push_str(&format!("<pre>{}</pre>\n", escape_html(&examples.join("\n"))));
+            }
+            if let Some(definition) = &word.definition {
+                // link the words the definition uses
+                let linked: Vec<String> = definition.split(' ').map(|w| {
+                    let bare = w.trim_matches(|c| c == '[' || c == ']');
+                    if bare.is_empty() || bare == word.name {
+                        escape_html(w)
+                    } else {
+                        escape_html(w).replacen(&escape_html(bare), &link(bare), 1)
+                    }
+                }).collect();
+                out.push_str(&format!("<pre>{}</pre>\n", linked.join(" ")));
+            }
+        }
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// Writes a reference of the words defined by `paths`, the prelude and the
+/// builtins to `dir` as HTML and Markdown. Returns whether it worked.
+fn doc_files(paths: &[String], dir: &str) -> bool {
+    let mut env = Env::new();
+    let mut stack = Vec::new();
+    env.static_check = false;
+    load_source(PRELUDE, &mut stack, &mut env);
+    let mut seen: HashSet<String> = env.programs.keys().cloned().collect();
+    let mut prelude: Vec<&String> = env.programs.keys().collect();
+    prelude.sort();
+    let prelude: Vec<WordDoc> = prelude.into_iter().map(|name| user_word_doc(name, &env)).collect();
+    env.checking = true;
+    let mut sections = Vec::new();
+    for path in paths {
+        if let Err(error) = include_file(Path::new(path), &mut stack, &mut env) {
+            println!("{}: {}", path, error);
+            return false;
+        }
+        let mut names: Vec<String> = env.programs.keys()
+            .filter(|name| !seen.contains(*name) && !is_hidden(name))
+            .cloned()
+            .collect();
+        names.sort();
+        seen.extend(names.iter().cloned());
+        sections.push((path.clone(), names.iter().map(|name| user_word_doc(name, &env)).collect()));
+    }
+    sections.push(("Prelude".to_string(), prelude));
+    sections.push(("Builtins".to_string(), BUILTINS.iter().map(|&name| WordDoc {
+        name: name.to_string(),
+        effect: signature(name).map(String::from),
+        doc: builtin_doc(name).unwrap_or("").to_string(),
+        definition: None
+    }).collect()));
+
+    let title = "Joy reference";
+    let outputs = [
+        (Path::new(dir).join("index.html"), render_html(title, &sections)),
+        (Path::new(dir).join("index.md"), render_markdown(title, &sections))
+    ];
+    if let Err(error) = fs::create_dir_all(dir) {
+        println!("cannot create {}: {}", dir, error);
+        return false;
+    }
+    for (path, text) in &outputs {
+        if let Err(error) = fs::write(path, text) {
+            println!("cannot write {}: {}", path.display(), error);
+            return false;
+        }
+        println!("wrote {}", path.display());
+    }
+    true
+}
+
+/// Formats Joy source in the canonical layout: single spaces between tokens
+/// and around `==`, definitions in `HIDE` and `MODULE` blocks indented, at
+/// most one blank line in a row, and quotations that do not fit in
+/// `LINE_WIDTH` broken over indented lines. Literals keep the form they were
+/// written in, and lines with comments are only reindented.
+fn format_source(source: &str) -> String {
+    let mut out: Vec<String> = Vec::new();
+    let mut depth = 0;
+    let mut comment = None;
+    for (_, text) in logical_lines(source) {
+        let in_comment = comment.is_some();
+        let (code, comments) = strip_comments(&text, &mut comment);
+        let code = code.trim();
+        let opens_test = code.split_whitespace().next() == Some("TEST");
+        let keywords: Vec<&str> = if is_block_line(code) || opens_test { code.split_whitespace().collect() } else { Vec::new() };
+        let closes = matches!(keywords.first(), Some(&("END" | "IN" | "PRIVATE" | "PUBLIC")));
+        let indent = " ".repeat(FMT_INDENT * if closes { depth.max(1) - 1 } else { depth });
+        if text.trim().is_empty() {
+            if out.last().is_some_and(|l| !l.is_empty()) {
+                out.push(String::new());
+            }
+        } else if in_comment || !comments.is_empty() || comment.is_some() || code.starts_with('#') {
+            // keep comments as written, reindenting a line that starts with one
+            let mut lines = text.lines().map(str::trim_end);
+            if let Some(first) = lines.next() {
+                out.push(if in_comment { first.to_string() } else { format!("{}{}", indent, first.trim_start()) });
+            }
+            out.extend(lines.map(String::from));
+        } else if !keywords.is_empty() {
+            out.push(format!("{}{}", indent, keywords.join(" ")));
+        } else {
+            out.extend(format_code(code, &indent).unwrap_or_else(|| vec![format!("{}{}", indent, code)]));
+        }
+        depth += keywords.iter().filter(|&&w| matches!(w, "HIDE" | "MODULE" | "TEST")).count();
+        depth -= keywords.iter().filter(|&&w| w == "END").count().min(depth);
+    }
+    while out.last().is_some_and(|l| l.is_empty()) {
+        out.pop();
+    }
+    out.iter().map(|l| format!("{}\n", l)).collect()
+}
+
+const FMT_INDENT: usize = 4;
+
+/// A token as written, so that the formatter keeps literals like `0xff` in
+/// the form their author chose.
+enum Lexeme {
+    Atom(String),
+    List(Vec<Lexeme>)
+}
+
+impl fmt::Display for Lexeme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lexeme::Atom(s) => write!(f, "{}", s),
+            Lexeme::List(items) => {
+                let items: Vec<String> = items.iter().map(|l| l.to_string()).collect();
+                write!(f, "[{}]", items.join(" "))
+            }
+        }
+    }
+}
+
+fn lexeme(s: &str) -> IResult<&str, Lexeme> {
+    alt((
+        map(delimited(pair(tag("["), opt(whitespace)), lexemes, pair(opt(whitespace), tag("]"))), Lexeme::List),
+        map(recognize(token), |t: &str| Lexeme::Atom(t.to_string()))
+    ))(s)
+}
+
+fn lexemes(s: &str) -> IResult<&str, Vec<Lexeme>> {
+    separated_list(whitespace, lexeme)(s)
+}
+
+/// Lays out a definition or a line of code, or `None` if it does not parse.
+fn format_code(code: &str, indent: &str) -> Option<Vec<String>> {
+    let (head, body) = match definition(code) {
+        Ok(("", (name, effect, body))) if !body.is_empty() => {
+            let effect = effect.map(|e| {
+                let inner: Vec<&str> = e[1..e.len() - 1].split_whitespace().collect();
+                format!(" ({})", inner.join(" "))
+            }).unwrap_or_default();
+            let rest = &code[code.find("==")? + 2..];
+            (format!("{}{}{} ==", indent, name, effect), rest.trim())
+        },
+        Ok(("", _)) => return None,
+        _ => (indent.to_string(), code)
+    };
+    let items = match lexemes(body) {
+        Ok(("", items)) => items,
+        _ => return None
+    };
+    let mut out = Vec::new();
+    let mut line = head;
+    layout(&items, indent.len(), false, &mut line, &mut out);
+    out.push(line);
+    Some(out)
+}
+
+/// Appends `items` to `line`, moving finished lines to `out`. Inside a
+/// quotation (`nested`) an item that does not fit goes on a new line at
+/// `indent`; outside, a line may only break inside a quotation, since the
+/// loader joins lines only while a `[` is open. A quotation that does not
+/// fit is opened at the end of the line, with its items indented below and
+/// its `]` on a line of its own.
+fn layout(items: &[Lexeme], indent: usize, nested: bool, line: &mut String, out: &mut Vec<String>) {
+    for item in items {
+        let text = item.to_string();
+        let sep = if line.trim().is_empty() { "" } else { " " };
+        if line.len() + sep.len() + text.len() <= LINE_WIDTH {
+            line.push_str(sep);
+            line.push_str(&text);
+            continue;
+        }
+        if nested && !line.trim().is_empty() {
+            out.push(std::mem::replace(line, " ".repeat(indent)));
+            if indent + text.len() <= LINE_WIDTH {
+                line.push_str(&text);
+                continue;
+            }
+        }
+        let sep = if line.trim().is_empty() { "" } else { " " };
+        match item {
+            Lexeme::List(inner) if !inner.is_empty() => {
+                line.push_str(sep);
+                line.push('[');
+                out.push(std::mem::replace(line, " ".repeat(indent + FMT_INDENT)));
+                layout(inner, indent + FMT_INDENT, true, line, out);
+                out.push(std::mem::replace(line, format!("{}]", " ".repeat(indent))));
+            },
+            _ => {
+                line.push_str(sep);
+                line.push_str(&text);
+            }
+        }
+    }
+}
+
+/// What a source file means, ignoring layout and plain comments: each
+/// docstring, block keyword, definition and line of tokens in order.
+fn source_meaning(source: &str) -> Vec<String> {
+    let mut meaning = Vec::new();
+    let mut comment = None;
+    for (_, text) in logical_lines(source) {
+        let (code, comments) = strip_comments(&text, &mut comment);
+        meaning.extend(comments.iter().filter_map(|c| docstring(c)));
+        let code = code.trim();
+        if code.is_empty() || code.starts_with('#') {
+            continue;
+        }
+        meaning.push(if is_block_line(code) {
+            code.split_whitespace().collect::<Vec<_>>().join(" ")
+        } else {
+            match (definition(code), expr(code)) {
+                (Ok(("", (name, effect, body))), _) => {
+                    let effect = effect.map(|e| e.split_whitespace().collect::<Vec<_>>().join(" "));
+                    format!("{} {:?} == {:?}", name, effect, body)
+                },
+                (_, Ok(("", toks))) => format!("{:?}", toks),
+                _ => code.to_string()
+            }
+        });
+    }
+    meaning
+}
+
+/// Formats files in place, or with `check` only reports those that are not
+/// formatted. Without paths, formats standard input to standard output.
+/// Returns whether everything was, or is now, formatted.
+fn fmt_files(paths: &[String], check: bool) -> bool {
+    if paths.is_empty() {
+        let mut source = String::new();
+        if io::stdin().read_to_string(&mut source).is_err() {
+            return false;
+        }
+        let formatted = format_source(&source);
+        if source_meaning(&formatted) != source_meaning(&source) {
+            eprintln!("formatting would change the meaning of the input");
+            return false;
+        }
+        print!("{}", formatted);
+        return !check || formatted == source;
+    }
+    let mut ok = true;
+    for path in paths {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                println!("cannot read {}: {}", path, error);
+                ok = false;
+                continue;
+            }
+        };
+        let formatted = format_source(&source);
+        if formatted == source {
+            continue;
+        }
+        if source_meaning(&formatted) != source_meaning(&source) {
+            println!("{}: formatting would change its meaning, so it is left as it is", path);
+            ok = false;
+        } else if check {
+            println!("{} is not formatted", path);
+            ok = false;
+        } else if let Err(error) = fs::write(path, formatted) {
+            println!("cannot write {}: {}", path, error);
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// The linter's rules, by stable ID and name. Either can be given to
+/// `--allow`, or to a `(* lint: allow ... *)` comment that covers its own
+/// line and the next, or `(* lint: allow-file ... *)` for the whole file.
+const LINT_RULES: &[(&str, &str)] = &[
+    ("J001", "shadowed-builtin"),
+    ("J002", "undefined-symbol"),
+    ("J003", "unreachable-code"),
+    ("J004", "no-op-pair"),
+    ("J005", "inline-quotation")
+];
+
+/// A token with the byte range of the line it was parsed from, for reports
+/// that point into the source.
+struct Spanned {
+    start: usize,
+    end: usize,
+    token: Token,
+    /// The items of a quotation.
+    items: Vec<Spanned>
+}
+
+/// Parses tokens from `s`, a suffix of `base`, with their positions in
+/// `base`. Stops at a `]` or at anything else that is not a token.
+fn spanned_tokens<'a>(base: &str, mut s: &'a str) -> (&'a str, Vec<Spanned>) {
+    let blank: &[char] = &[' ', '\t', '\n'];
+    let mut out = Vec::new();
+    loop {
+        let rest = s.trim_start_matches(blank);
+        let start = base.len() - rest.len();
+        if let Some(inner) = rest.strip_prefix('[') {
+            let (after, items) = spanned_tokens(base, inner);
+            match after.trim_start_matches(blank).strip_prefix(']') {
+                Some(after) => {
+                    let token = Token::Quotation(items.iter().map(|i| i.token.clone()).collect());
+                    out.push(Spanned { start, end: base.len() - after.len(), token, items });
+                    s = after;
+                },
+                None => return (rest, out)
+            }
+        } else {
+            match token(rest) {
+                Ok((after, token)) => {
+                    out.push(Spanned { start, end: base.len() - after.len(), token, items: Vec::new() });
+                    s = after;
+                },
+                Err(_) => return (rest, out)
+            }
+        }
+    }
+}
+
+/// Finds the lint in a sequence of tokens and the quotations in it, as
+/// (start, end, rule, message).
+fn lint_tokens(items: &[Spanned], known: &HashSet<String>, out: &mut Vec<(usize, usize, &'static str, String)>) {
+    for (k, item) in items.iter().enumerate() {
+        let next = items.get(k + 1);
+        let next_sym = match next.map(|n| &n.token) {
+            Some(Token::Symbol(s)) => Some(s.as_str()),
+            _ => None
+        };
+        match &item.token {
+            Token::Symbol(sym) => {
+                let after_use = k > 0 && matches!(&items[k - 1].token, Token::Symbol(s) if s == "use");
+                if !after_use && !known.contains(sym) && !BUILTINS.contains(&sym.as_str()) {
+                    out.push((item.start, item.end, "undefined-symbol", format!("`{}` is not defined", sym)));
+                }
+                if let (true, Some(next), Some(last)) = (matches!(sym.as_str(), "quit" | "abort" | "throw"), next, items.last()) {
+                    out.push((next.start, last.end, "unreachable-code", format!("nothing after `{}` runs", sym)));
+                }
+                if let Some(other) = next_sym {
+                    if (sym == "dup" && other == "pop") || (sym == "swap" && other == "swap") {
+                        out.push((item.start, next.unwrap().end, "no-op-pair", format!("`{} {}` does nothing", sym, other)));
+                    }
+                }
+            },
+            Token::Quotation(_) => {
+                if next_sym == Some("i") {
+                    out.push((item.start, next.unwrap().end, "inline-quotation",
+                        "a quotation run at once by `i` can be written inline".to_string()));
+                }
+                lint_tokens(&item.items, known, out);
+            },
+            _ => {}
+        }
+    }
+}
+
+/// The rules a `(* lint: allow ... *)` or `(* lint: allow-file ... *)`
+/// comment names, with whether they cover the whole file.
+fn lint_allows(comment: &str) -> Option<(bool, Vec<&'static str>)> {
+    let mut words = comment.trim().strip_prefix("lint:")?.split_whitespace();
+    let file = match words.next()? {
+        "allow" => false,
+        "allow-file" => true,
+        _ => return None
+    };
+    Some((file, words.filter_map(lint_rule).collect()))
+}
+
+/// The name of a rule given by ID or name.
+fn lint_rule(id: &str) -> Option<&'static str> {
+    LINT_RULES.iter().find(|(code, name)| *code == id || *name == id).map(|(_, name)| *name)
+}
+
+/// Lints a file, printing what it finds unless `allowed`. Returns how many
+/// findings it printed.
+fn lint_file(path: &str, allowed: &[&str]) -> usize {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            println!("cannot read {}: {}", path, error);
+            return 1;
+        }
+    };
+    let mut env = Env::new();
+    let mut stack = Vec::new();
+    env.static_check = false;
+    load_source(PRELUDE, &mut stack, &mut env);
+    env.checking = true;
+    // a file that fails to load is still linted, as far as its own definitions go
+    let failed = include_file(Path::new(path), &mut stack, &mut env).err();
+    if let Some(error) = &failed {
+        println!("{}: {}", path, error);
+    }
+    let lines = logical_lines(&source);
+    let mut known: HashSet<String> = env.programs.keys().cloned().collect();
+    for (_, text) in &lines {
+        if let Ok(("", (name, _, _))) = definition(text.trim()) {
+            known.insert(name);
+        }
+    }
+
+    // (line, start, end, rule, message), with the rules allowed per line
+    let mut found = Vec::new();
+    let mut allowed: Vec<&str> = allowed.to_vec();
+    let mut line_allows: HashMap<usize, Vec<&str>> = HashMap::new();
+    let mut comment = None;
+    // for each open block, whether it is a module and whether it is in its public part
+    let mut blocks: Vec<(bool, bool)> = Vec::new();
+    let mut in_test = false;
+    for (k, (_, text)) in lines.iter().enumerate() {
+        let (code, comments) = strip_comments(text, &mut comment);
+        for (file, rules) in comments.iter().filter_map(|c| lint_allows(c)) {
+            if file {
+                allowed.extend(rules);
+            } else {
+                line_allows.entry(k).or_default().extend(rules.iter().copied());
+                line_allows.entry(k + 1).or_default().extend(rules);
+            }
+        }
+        let trimmed = code.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if trimmed.split_whitespace().next() == Some("TEST") || (in_test && trimmed == "END") {
+            in_test = !in_test;
+            continue;
+        }
+        if is_block_line(trimmed) && !in_test {
+            let mut words = trimmed.split_whitespace();
+            while let Some(word) = words.next() {
+                match word {
+                    "HIDE" => blocks.push((false, false)),
+                    "MODULE" => {
+                        words.next();
+                        blocks.push((true, true));
+                    },
+                    "IN" | "PUBLIC" => blocks.last_mut().iter_mut().for_each(|b| b.1 = true),
+                    "PRIVATE" => blocks.last_mut().iter_mut().for_each(|b| b.1 = false),
+                    _ => {
+                        blocks.pop();
+                    }
+                }
+            }
+            continue;
+        }
+        let mut findings = Vec::new();
+        let body_start = match definition(trimmed) {
+            Ok(("", (name, _, _))) => {
+                // only plain names outside modules and private parts replace a builtin
+                let reachable = blocks.iter().all(|&(module, public)| !module && public);
+                if reachable && BUILTINS.contains(&name.as_str()) {
+                    let start = code.len() - code.trim_start().len();
+                    findings.push((start, start + name.len(), "shadowed-builtin",
+                        format!("`{}` is a builtin, so this definition is never used", name)));
+                }
+                code.find("==").map_or(0, |i| i + 2)
+            },
+            _ => 0
+        };
+        let (_, items) = spanned_tokens(&code, &code[body_start..]);
+        lint_tokens(&items, &known, &mut findings);
+        found.extend(findings.into_iter().map(|(start, end, rule, message)| (k, start, end, rule, message)));
+    }
+
+    let position = |k: usize, offset: usize| {
+        let (first, text) = &lines[k];
+        let before = &text[..offset];
+        let line = first + before.matches('\n').count();
+        let col = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        (line, col)
+    };
+    let mut reports: Vec<((usize, usize), String)> = found.into_iter()
+        .filter(|(k, _, _, rule, _)| !allowed.contains(rule) && !line_allows.get(k).is_some_and(|a| a.contains(rule)))
+        .map(|(k, start, end, rule, message)| {
+            let (line, col) = position(k, start);
+            let (end_line, end_col) = position(k, end);
+            let span = if end_line == line {
+                format!("{}:{}-{}", line, col, end_col)
+            } else {
+                format!("{}:{}-{}:{}", line, col, end_line, end_col)
+            };
+            let id = LINT_RULES.iter().find(|r| r.1 == rule).map_or("", |r| r.0);
+            ((line, col), format!("{}:{}: {} {}: {}", path, span, id, rule, message))
+        })
+        .collect();
+    reports.sort();
+    for (_, report) in &reports {
+        println!("{}", report);
+    }
+    reports.len() + failed.iter().count()
+}
+
+/// Output written by a test, kept for its report.
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Captured {
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The outcome of one test.
+struct TestResult {
+    /// The file the test comes from.
+    suite: String,
+    name: String,
+    /// Why the test failed, if it did.
+    error: Option<JoyError>,
+    output: String,
+    seconds: f64
+}
+
+/// The `.joy` files among `paths`, with directories searched recursively.
+fn joy_files(paths: &[String]) -> Vec<PathBuf> {
+    fn walk(dir: &Path, out: &mut Vec<PathBuf>) {
+        let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+            Err(error) => {
+                println!("cannot read {}: {}", dir.display(), error);
+                return;
+            }
+        };
+        entries.sort();
+        for path in entries {
+            if path.is_dir() {
+                walk(&path, out);
+            } else if path.extension().is_some_and(|e| e == "joy") {
+                out.push(path);
+            }
+        }
+    }
+    let mut files = Vec::new();
+    for path in paths.iter().map(PathBuf::from) {
+        if path.is_dir() {
+            walk(&path, &mut files);
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
+/// A fresh interpreter for a test, with the prelude and `path` loaded and
+/// its output captured.
+fn test_env(path: Option<&Path>, output: &Captured) -> Result<(Env, Vec<Token>), JoyError> {
+    let mut env = Env::with_io(Box::new(output.clone()), Box::new(io::empty()));
+    let mut stack = Vec::new();
+    env.static_check = false;
+    load_source(PRELUDE, &mut stack, &mut env);
+    if let Some(path) = path {
+        include_file(path, &mut stack, &mut env)?;
+    }
+    Ok((env, stack))
+}
+
+/// Runs the `TEST` blocks of the `.joy` files in `paths`, each in a fresh
+/// interpreter. A file that fails to load counts as one failed test.
+fn run_tests(paths: &[String]) -> Vec<TestResult> {
+    let mut results = Vec::new();
+    for file in joy_files(paths) {
+        let suite = file.display().to_string();
+        let output = Captured::default();
+        let tests = match test_env(Some(&file), &output) {
+            Ok((env, _)) => env.tests,
+            Err(error) => {
+                results.push(TestResult {
+                    suite, name: "(load)".to_string(), error: Some(error), output: output.text(), seconds: 0.0
+                });
+                continue;
+            }
+        };
+        for (name, body) in tests {
+            let output = Captured::default();
+            let start = Instant::now();
+            let error = test_env(Some(&file), &output).and_then(|(mut env, mut stack)| {
+                for line in &body {
+                    if run_line(line, &mut stack, &mut env)? {
+                        break;
+                    }
+                }
+                Ok(())
+            }).err();
+            results.push(TestResult {
+                suite: suite.clone(), name, error, output: output.text(), seconds: start.elapsed().as_secs_f64()
+            });
+        }
+    }
+    results
+}
+
+/// Runs the `==> program  ⇒  stack` examples in the docstrings of the words
+/// defined in the `.joy` files in `paths`, or of the builtins and the
+/// prelude when there are none, each in a fresh interpreter. The stack an
+/// example leaves must read as shown after `⇒`; an example without `⇒`
+/// only has to run.
+fn run_doctests(paths: &[String]) -> Vec<TestResult> {
+    fn examples(suite: &str, file: Option<&Path>, docs: Vec<(String, String)>) -> Vec<TestResult> {
+        let mut results = Vec::new();
+        for (word, doc) in docs {
+            for example in doc_examples(&doc).1 {
+                let example = example.trim_start_matches("==>");
+                let (program, expected) = match example.split_once('⇒') {
+                    Some((program, expected)) => (program.trim(), Some(expected.trim())),
+                    None => (example.trim(), None)
+                };
+                let output = Captured::default();
+                let start = Instant::now();
+                let error = test_env(file, &output).and_then(|(mut env, mut stack)| {
+                    run_line(program, &mut stack, &mut env)?;
+                    match expected {
+                        Some(expected) if expected != joy_text(&stack) => Err(JoyError::new(
+                            ErrorKind::Assertion, &word, differs("stacks", expected, &joy_text(&stack))
+                        )),
+                        _ => Ok(())
+                    }
+                }).err();
+                results.push(TestResult {
+                    suite: suite.to_string(), name: format!("{}: {}", word, program), error,
+                    output: output.text(), seconds: start.elapsed().as_secs_f64()
+                });
+            }
+        }
+        results
+    }
+
+    let mut results = Vec::new();
+    let prelude = match test_env(None, &Captured::default()) {
+        Ok((env, _)) => env.docs,
+        Err(_) => HashMap::new()
+    };
+    let sorted = |docs: HashMap<String, String>| {
+        let mut docs: Vec<(String, String)> = docs.into_iter().collect();
+        docs.sort();
+        docs
+    };
+    if paths.is_empty() {
+        let builtins = BUILTIN_DOCS.iter().map(|(name, doc)| (name.to_string(), doc.to_string())).collect();
+        results.extend(examples("builtins", None, builtins));
+        results.extend(examples("prelude", None, sorted(prelude.clone())));
+    }
+    for file in joy_files(paths) {
+        let suite = file.display().to_string();
+        let output = Captured::default();
+        match test_env(Some(&file), &output) {
+            Ok((env, _)) => {
+                let docs = env.docs.into_iter()
+                    .filter(|(name, doc)| !is_hidden(name) && prelude.get(name) != Some(doc))
+                    .collect();
+                results.extend(examples(&suite, Some(&file), sorted(docs)));
+            },
+            Err(error) => results.push(TestResult {
+                suite, name: "(load)".to_string(), error: Some(error), output: output.text(), seconds: 0.0
+            })
+        }
+    }
+    results
+}
+
+/// The results as JUnit XML, with a test suite per file. Failed assertions
+/// are failures and other errors are errors.
+fn junit_xml(results: &[TestResult]) -> String {
+    let mut suites: Vec<(&str, Vec<&TestResult>)> = Vec::new();
+    for result in results {
+        match suites.iter_mut().find(|(suite, _)| *suite == result.suite) {
+            Some((_, tests)) => tests.push(result),
+            None => suites.push((&result.suite, vec![result]))
+        }
+    }
+    let count = |tests: &[&TestResult], assertion: bool| tests.iter()
+        .filter(|t| t.error.as_ref().is_some_and(|e| (e.kind == ErrorKind::Assertion) == assertion))
+        .count();
+    let all: Vec<&TestResult> = results.iter().collect();
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str(&format!("<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\">\n",
+        all.len(), count(&all, true), count(&all, false)));
+    for (suite, tests) in &suites {
+        let seconds: f64 = tests.iter().map(|t| t.seconds).sum();
+        out.push_str(&format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">\n",
+            escape_html(suite), tests.len(), count(tests, true), count(tests, false), seconds));
+        for test in tests {
+            out.push_str(&format!("    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+                escape_html(&test.name), escape_html(suite), test.seconds));
+            if test.error.is_none() && test.output.is_empty() {
+                out.push_str("/>\n");
+                continue;
+            }
+            out.push_str(">\n");
+            if let Some(error) = &test.error {
+                let tag = if error.kind == ErrorKind::Assertion { "failure" } else { "error" };
+                out.push_str(&format!("      <{} type=\"{}\" message=\"{}\">{}</{}>\n",
+                    tag, error.kind.name(), escape_html(error.message.lines().next().unwrap_or("")),
+                    escape_html(&error.to_string()), tag));
+            }
+            if !test.output.is_empty() {
+                out.push_str(&format!("      <system-out>{}</system-out>\n", escape_html(&test.output)));
+            }
+            out.push_str("    </testcase>\n");
+        }
+        out.push_str("  </testsuite>\n");
+    }
+    out.push_str("</testsuites>\n");
+    out
+}
+
+/// Prints the results, and writes them as JUnit XML to `junit` if given.
+/// Returns whether every test passed.
+fn report_tests(results: &[TestResult], junit: Option<&str>) -> bool {
+    for result in results {
+        match &result.error {
+            None => println!("test {}: {} ... ok", result.suite, result.name),
+            Some(error) => {
+                println!("test {}: {} ... FAILED", result.suite, result.name);
+                for line in error.to_string().lines() {
+                    println!("    {}", line);
+                }
+                if !result.output.is_empty() {
+                    println!("    output:");
+                    for line in result.output.lines() {
+                        println!("      {}", line);
+                    }
+                }
+            }
+        }
+    }
+    let failed = results.iter().filter(|r| r.error.is_some()).count();
+    println!("\n{} passed, {} failed", results.len() - failed, failed);
+    if let Some(path) = junit {
+        if let Err(error) = fs::write(path, junit_xml(results)) {
+            println!("cannot write {}: {}", path, error);
+            return false;
+        }
+    }
+    failed == 0
+}
+
+/// Limits for printing the stack at the REPL.
+const SHOWN_ITEMS: usize = 16;
+const SHOWN_ELEMENTS: usize = 24;
+const SHOWN_DEPTH: usize = 6;
+const LINE_WIDTH: usize = 78;
+
+/// Like `Display`, but long quotations are cut after `SHOWN_ELEMENTS`
+/// elements and ones nested deeper than `depth` are shown as `[...]`.
+fn abbreviate(t: &Token, depth: usize) -> String {
+    match t {
+        Token::Quotation(q) if depth == 0 && !q.is_empty() => "[...]".to_string(),
+        Token::Quotation(q) => {
+            let mut parts: Vec<String> = q.iter()
+                .take(SHOWN_ELEMENTS)
+                .map(|t| abbreviate(t, depth - 1))
+                .collect();
+            if q.len() > SHOWN_ELEMENTS {
+                parts.push(format!("...{} more", q.len() - SHOWN_ELEMENTS));
+            }
+            format!("[{}]", parts.join(" "))
+        },
+        t => t.to_string()
+    }
+}
+
+/// The stack as the REPL shows it: bottom to top in Joy syntax, wrapped at
+/// `LINE_WIDTH`, with the top marked and anything below the top
+/// `SHOWN_ITEMS` elided.
+fn format_stack(stack: &[Token]) -> String {
+    if stack.is_empty() {
+        return "(empty stack)".to_string();
+    }
+    let hidden = stack.len().saturating_sub(SHOWN_ITEMS);
+    let mut items: Vec<String> = Vec::new();
+    if hidden > 0 {
+        items.push(format!("...{} more", hidden));
+    }
+    items.extend(stack[hidden..].iter().map(|t| abbreviate(t, SHOWN_DEPTH)));
+    items.push("<- top".to_string());
+
+    let mut out = String::new();
+    let mut column = 0;
+    for item in items {
+        let len = item.chars().count();
+        if column > 0 && column + 1 + len > LINE_WIDTH {
+            out.push_str("\n    ");
+            column = 4;
+        } else if column > 0 {
+            out.push(' ');
+            column += 1;
+        }
+        out.push_str(&item);
+        column += len;
+    }
+    out
+}
+
+/// Whether `line` consists only of the keywords that open, divide and close
+/// `HIDE` and `MODULE` blocks.
+fn is_block_line(line: &str) -> bool {
+    let mut words = line.split_whitespace().peekable();
+    (words.peek().is_some() && words.all(|w| matches!(w, "HIDE" | "IN" | "MODULE" | "PRIVATE" | "PUBLIC" | "END")))
+        || line.split_whitespace().next() == Some("MODULE")
+}
+
+/// Names of private definitions contain a space, so no program can spell them.
+fn is_hidden(name: &str) -> bool {
+    name.contains(' ')
+}
+
+/// Replaces the symbols in `body`, quotations included, that `names` maps.
+fn rename(body: Vec<Token>, names: &HashMap<String, String>) -> Vec<Token> {
+    body.into_iter().map(|t| match t {
+        Token::Symbol(s) => Token::Symbol(names.get(&s).cloned().unwrap_or(s)),
+        Token::Quotation(q) => Token::Quotation(rename(q, names)),
+        t => t
+    }).collect()
+}
+
+/// Runs a line of block keywords. At `END` the names of the block are
+/// resolved: private ones get hidden names and public ones of a module are
+/// qualified as `m.word`, then everything goes to the enclosing block or,
+/// at the top level, to the dictionary.
+fn block_line(line: &str, env: &mut Env) -> Result<(), JoyError> {
+    let mut words = line.split_whitespace();
+    while let Some(word) = words.next() {
+        let error = |message: &str| Err(JoyError::new(ErrorKind::Value, word, message.to_string()));
+        match (word, env.blocks.last_mut()) {
+            ("HIDE", _) => env.blocks.push(Block::new(None, false)),
+            ("MODULE", _) => match words.next() {
+                Some(name) if !is_block_line(name) => env.blocks.push(Block::new(Some(name.to_string()), true)),
+                _ => return error("`MODULE` needs a name")
+            },
+            ("IN", Some(block)) if block.module.is_none() => block.public = true,
+            ("PRIVATE", Some(block)) | ("PUBLIC", Some(block)) if block.module.is_some() => block.public = word == "PUBLIC",
+            ("END", Some(_)) => {
+                let block = env.blocks.pop().unwrap();
+                let mut names = HashMap::new();
+                for (name, _) in &block.private {
+                    env.hidden += 1;
+                    names.insert(name.clone(), format!("{} #{}", name, env.hidden));
+                }
+                for (name, _) in &block.exported {
+                    let qualified = match &block.module {
+                        Some(m) => format!("{}.{}", m, name),
+                        None => name.clone()
+                    };
+                    names.insert(name.clone(), qualified);
+                }
+                let resolve = |defs: Vec<(String, Vec<Token>)>| -> Vec<(String, Vec<Token>)> {
+                    defs.into_iter()
+                        .map(|(name, body)| (names.get(&name).cloned().unwrap_or(name), rename(body, &names)))
+                        .collect()
+                };
+                let mut hidden = resolve(block.hidden);
+                hidden.extend(resolve(block.private));
+                let exported = resolve(block.exported);
+                let resolve_name = |(name, text): (String, String)| (names.get(&name).cloned().unwrap_or(name), text);
+                let effects = block.effects.into_iter().map(resolve_name);
+                let docs = block.docs.into_iter().map(resolve_name);
+                match env.blocks.last_mut() {
+                    Some(outer) => {
+                        outer.effects.extend(effects);
+                        outer.docs.extend(docs);
+                        outer.hidden.extend(hidden);
+                        if outer.public {
+                            outer.exported.extend(exported);
+                        } else {
+                            outer.private.extend(exported);
+                        }
+                    },
+                    None => {
+                        let names: Vec<String> = hidden.iter().chain(&exported).map(|d| d.0.clone()).collect();
+                        for name in &names {
+                            env.effects.remove(name);
+                            env.docs.remove(name);
+                        }
+                        env.effects.extend(effects);
+                        env.docs.extend(docs);
+                        env.programs.extend(hidden.into_iter().chain(exported));
+                        for name in names {
+                            report_definition(&name, env);
+                        }
+                    }
+                }
+            },
+            ("IN", _) => return error("`IN` outside of `HIDE`"),
+            ("PRIVATE", _) | ("PUBLIC", _) => return error("`PRIVATE` and `PUBLIC` belong in a `MODULE`"),
+            ("END", None) => return error("`END` without `HIDE` or `MODULE`"),
+            _ => return error("expected a block keyword")
+        }
+    }
+    Ok(())
+}
+
+/// The characters of `line` outside of string and character literals,
+/// with their positions.
+fn code_chars(line: &str) -> Vec<(usize, char)> {
+    let mut code = Vec::new();
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                while let Some((_, d)) = chars.next() {
+                    match d {
+                        '\\' => {
+                            chars.next();
+                        },
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            },
+            // the parser tells a char like `'a` from a string like `'a b'`
+            '\'' => match character(&line[i..]) {
+                Ok((rest, _)) => {
+                    let end = line.len() - rest.len();
+                    while chars.next_if(|&(j, _)| j < end).is_some() {}
+                },
+                Err(_) => {
+                    while let Some((_, d)) = chars.next() {
+                        match d {
+                            '\\' => {
+                                chars.next();
+                            },
+                            '\'' => break,
+                            _ => {}
+                        }
+                    }
+                }
+            },
+            _ => code.push((i, c))
+        }
+    }
+    code
+}
+
+/// Where a `(*` comment starts in `line`, outside of strings.
+fn comment_start(line: &str) -> Option<usize> {
+    code_chars(line).into_iter()
+        .find(|&(i, c)| c == '(' && line[i..].starts_with("(*"))
+        .map(|(i, _)| i)
+}
+
+/// Joins the lines of a source file into the lines the loader runs: a line
+/// goes on to the next while it has a `[` open, so that long quotations can
+/// be broken up. Each comes with the number of its first line.
+fn logical_lines(source: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut comment = None;
+    let mut current: Option<(usize, String, i64)> = None;
+    for (i, line) in source.lines().enumerate() {
+        let code = strip_comments(line, &mut comment).0;
+        let depth: i64 = code_chars(&code).into_iter().map(|(_, c)| match c {
+            '[' => 1,
+            ']' => -1,
+            _ => 0
+        }).sum();
+        let (n, text, open) = match current.take() {
+            Some((n, text, open)) => (n, text + "\n" + line, open + depth),
+            None => (i + 1, line.to_string(), depth)
+        };
+        if open > 0 {
+            current = Some((n, text, open));
+        } else {
+            lines.push((n, text));
+        }
+    }
+    lines.extend(current.map(|(n, text, _)| (n, text)));
+    lines
+}
+
+/// Splits a line into its code and the comments that end on it. `open` is
+/// the text of a comment still open from the lines before, and is left
+/// holding one still open at the end of this line. Comments are blanked out
+/// of the code rather than removed, so positions in it match the line.
+fn strip_comments(line: &str, open: &mut Option<String>) -> (String, Vec<String>) {
+    let blank = |s: &str| -> String {
+        s.chars().map(|c| if c == '\n' { "\n".to_string() } else { " ".repeat(c.len_utf8()) }).collect()
+    };
+    let mut code = String::new();
+    let mut done = Vec::new();
+    let mut rest = line;
+    loop {
+        match open.take() {
+            Some(mut text) => match rest.find("*)") {
+                Some(i) => {
+                    text.push_str(&rest[..i]);
+                    code.push_str(&blank(&rest[..i + 2]));
+                    done.push(text);
+                    rest = &rest[i + 2..];
+                },
+                None => {
+                    text.push_str(rest);
+                    text.push('\n');
+                    code.push_str(&blank(rest));
+                    *open = Some(text);
+                    return (code, done);
+                }
+            },
+            None => match comment_start(rest) {
+                Some(i) => {
+                    code.push_str(&rest[..i]);
+                    code.push_str("  ");
+                    *open = Some(String::new());
+                    rest = &rest[i + 2..];
+                },
+                None => {
+                    code.push_str(rest);
+                    return (code, done);
+                }
+            }
+        }
+    }
+}
+
+/// The text of a `(** ... *)` comment, given what is between `(*` and `*)`,
+/// with its lines trimmed.
+fn docstring(comment: &str) -> Option<String> {
+    let text = comment.strip_prefix('*')?;
+    let lines: Vec<&str> = text.lines().map(str::trim).collect();
+    Some(lines.join("\n").trim().to_string())
+}
+
+/// Runs one line of source: either a definition or a sequence of tokens.
+/// Returns whether the program asked to quit.
+fn run_line(input: &str, stack: &mut Vec<Token>, env: &mut Env) -> Result<bool, JoyError> {
+    let (code, comments) = strip_comments(input, &mut env.comment);
+    if let Some(doc) = comments.iter().rev().find_map(|c| docstring(c)) {
+        env.doc = Some(doc);
+    }
+    let input = code.trim();
+    if input.is_empty() {
+        return Ok(false);
+    }
+    let doc = env.doc.take();
+    if let Some((_, body)) = &mut env.test {
+        if input == "END" {
+            env.tests.extend(env.test.take());
+        } else {
+            body.push(input.to_string());
+        }
+        return Ok(false);
+    }
+    if input.split_whitespace().next() == Some("TEST") {
+        return match input.split_whitespace().collect::<Vec<_>>()[..] {
+            [_, name] if env.blocks.is_empty() => {
+                env.test = Some((name.to_string(), Vec::new()));
+                Ok(false)
+            },
+            [_, _] => Err(JoyError::new(ErrorKind::Value, "TEST", "`TEST` cannot be inside `HIDE` or `MODULE`".to_string())),
+            _ => Err(JoyError::new(ErrorKind::Value, "TEST", "`TEST` needs a name".to_string()))
+        };
+    }
+    if is_block_line(input) {
+        block_line(input, env)?;
+        return Ok(false);
+    }
+    if let Ok(("", (sym, effect, defn))) = definition(input) {
+        match env.blocks.last_mut() {
+            Some(block) => {
+                if let Some(effect) = effect {
+                    block.effects.insert(sym.clone(), effect);
+                }
+                if let Some(doc) = doc {
+                    block.docs.insert(sym.clone(), doc);
+                }
+                if block.public {
+                    block.exported.push((sym, defn));
+                } else {
+                    block.private.push((sym, defn));
+                }
+            },
+            None => {
+                match effect {
+                    Some(effect) => env.effects.insert(sym.clone(), effect),
+                    None => env.effects.remove(&sym)
+                };
+                match doc {
+                    Some(doc) => env.docs.insert(sym.clone(), doc),
+                    None => env.docs.remove(&sym)
+                };
+                env.programs.insert(sym.clone(), defn);
+                report_definition(&sym, env);
+            }
+        }
+        return Ok(false);
+    }
+    if !env.blocks.is_empty() {
+        return Err(JoyError::new(ErrorKind::Value, "MODULE", "only definitions are allowed inside `HIDE` and `MODULE`".to_string()));
+    }
+    if env.checking && !matches!(input.split_whitespace().next(), Some("include" | "use")) {
+        return Ok(false);
+    }
+    let mut toks = Vec::new();
+    let mut new_input: &str = input.trim();
+    while !new_input.is_empty() {
+        let parser = tuple((opt(whitespace), token));
+        match parser(new_input) {
+            Ok((left, tok)) => {
+                toks.push(tok.1);
+                new_input = left;
+            },
+            _ => {
+                let rest = new_input.trim_start();
+                return Err(JoyError {
+                    kind: ErrorKind::Syntax,
+                    message: format!("cannot parse `{}`", rest),
+                    token: Token::String(rest.to_string())
+                });
+            }
+        }
+    }
+    exec(toks, stack, env)
+}
+
+/// Runs one line of REPL input. Errors are reported and stop the rest of the
+/// line. Returns whether the program asked to quit.
+fn eval_line(input: &str, stack: &mut Vec<Token>, env: &mut Env) -> bool {
+    match run_line(input, stack, env) {
+        Ok(quit) => quit,
+        Err(error) => {
+            println!("{}", error);
+            false
+        }
+    }
+}
+
+/// Runs a file of Joy source line by line, skipping blank lines and `#`
+/// comments. Returns whether the program asked to quit.
+fn load_source(source: &str, stack: &mut Vec<Token>, env: &mut Env) -> bool {
+    for (_, line) in logical_lines(source) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if eval_line(line, stack, env) {
+            return true;
+        }
+    }
+    false
+}
+
+/// The standard library, written in Joy and loaded at startup.
+const PRELUDE: &str = include_str!("prelude.joy");
+
+/// The startup files to run after the prelude: `~/.joyrc`, then `.joyrc`
+/// in the current directory unless it is the same file.
+fn rc_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(home) = std::env::var_os("HOME") {
+        paths.push(Path::new(&home).join(".joyrc"));
+    }
+    let local = PathBuf::from(".joyrc");
+    let same = paths.first().is_some_and(|home| {
+        matches!((fs::canonicalize(home), fs::canonicalize(&local)), (Ok(a), Ok(b)) if a == b)
+    });
+    if !same {
+        paths.push(local);
+    }
+    paths
+}
+
+fn format_definition(name: &str, effect: Option<&String>, body: &[Token]) -> String {
+    let body: Vec<String> = body.iter().map(|t| t.to_string()).collect();
+    match effect {
+        Some(effect) => format!("{} {} == {}", name, effect, body.join(" ")),
+        None => format!("{} == {}", name, body.join(" "))
+    }
+}
+
+/// The definitions `names` as Joy source for `:save`. Hidden definitions
+/// cannot be written by name, so they go back into a `HIDE` block under
+/// names of their own, with the words that use them after its `IN`.
+fn save_source(names: &[&String], env: &Env) -> String {
+    fn mentions(body: &[Token], names: &HashMap<String, String>) -> bool {
+        body.iter().any(|t| match t {
+            Token::Symbol(s) => names.contains_key(s),
+            Token::Quotation(q) => mentions(q, names),
+            _ => false
+        })
+    }
+    let mut names = names.to_vec();
+    names.sort();
+    let (hidden, public): (Vec<&String>, Vec<&String>) = names.into_iter().partition(|name| is_hidden(name));
+    let mut local: HashMap<String, String> = HashMap::new();
+    for &name in &hidden {
+        let base = name.split(' ').next().unwrap_or(name);
+        let taken = |n: &String| env.programs.contains_key(n) || local.values().any(|l| l == n);
+        let mut pick = base.to_string();
+        let mut n = 1;
+        while taken(&pick) {
+            n += 1;
+            pick = format!("{}-{}", base, n);
+        }
+        local.insert(name.clone(), pick);
+    }
+    let (inside, outside): (Vec<&String>, Vec<&String>) = public.into_iter()
+        .partition(|name| mentions(&env.programs[*name], &local));
+
+    let definition = |name: &String, indent: &str| {
+        let body = rename(env.programs[name].clone(), &local);
+        let shown = local.get(name).unwrap_or(name);
+        let doc = env.docs.get(name).map(|d| format!("{}(** {} *)\n", indent, d)).unwrap_or_default();
+        format!("{}{}{}\n", doc, indent, format_definition(shown, env.effects.get(name), &body))
+    };
+    let indent = " ".repeat(FMT_INDENT);
+    let mut source: String = outside.iter().map(|name| definition(name, "")).collect();
+    if !hidden.is_empty() {
+        source.push_str("HIDE\n");
+        source.extend(hidden.iter().map(|name| definition(name, &indent)));
+        source.push_str("IN\n");
+        source.extend(inside.iter().map(|name| definition(name, &indent)));
+        source.push_str("END\n");
+    }
+    source
+}
+
+const META_HELP: &str = "\
+:help [word]   show this help, or what `word` is
+:defs          list the user definitions
+:show word     print the definition of `word`
+:undef word    delete the definition of `word`
+:clear         empty the stack
+:load file     run a file of Joy source
+:save file     write the user definitions to a file as Joy source
+:stack         print the stack
+:quit          leave the interpreter";
+
+/// Handles a `:` command typed at the REPL. Returns whether to quit.
+fn meta_command(line: &str, stack: &mut Vec<Token>, env: &mut Env) -> bool {
+    let mut parts = line.trim().splitn(2, char::is_whitespace);
+    let cmd = parts.next().unwrap_or("");
+    let arg = parts.next().unwrap_or("").trim();
+    match (cmd, arg) {
+        (":help", "") => println!("{}", META_HELP),
+        (":help", word) => match env.programs.get(word) {
+            Some(body) => {
+                println!("{}", format_definition(word, env.effects.get(word), body));
+                if let (Some(effect), _) = infer_effect(word, body, env) {
+                    println!("inferred stack effect {}", effect);
+                }
+                if let Some(doc) = env.docs.get(word) {
+                    println!("{}", doc);
+                }
+            },
+            None if BUILTINS.contains(&word) => {
+                println!("`{}` is a builtin {}", word, signature(word).unwrap_or(""));
+                println!("{}", builtin_doc(word).unwrap_or(""));
+            },
+            None => println!("`{}` is not defined", word)
+        },
+        (":defs", _) => {
+            let mut names: Vec<&String> = env.programs.keys().filter(|n| !is_hidden(n)).collect();
+            names.sort();
+            for name in names {
+                println!("{}", name);
+            }
+        },
+        (":show", word) | (":undef", word) if word.is_empty() => println!("{} needs a word", cmd),
+        (":show", word) => match env.programs.get(word) {
+            Some(body) => println!("{}", format_definition(word, env.effects.get(word), body)),
+            None => println!("`{}` is not a user definition", word)
+        },
+        (":undef", word) => {
+            env.effects.remove(word);
+            env.docs.remove(word);
+            if env.programs.remove(word).is_none() {
+                println!("`{}` is not a user definition", word);
+            }
+        },
+        (":clear", _) => stack.clear(),
+        (":load", "") | (":save", "") => println!("{} needs a file name", cmd),
+        (":load", path) => match fs::read_to_string(path) {
+            Ok(source) => return load_source(&source, stack, env),
+            Err(error) => println!("cannot read {}: {}", path, error)
+        },
+        (":save", path) => {
+            let names: Vec<&String> = env.programs.iter()
+                .filter(|(name, body)| env.prelude.get(*name).is_none_or(|p| joy_text(p) != joy_text(body)))
+                .map(|(name, _)| name)
+                .collect();
+            if let Err(error) = fs::write(path, save_source(&names, env)) {
+                println!("cannot write {}: {}", path, error);
+            }
+        },
+        (":stack", _) => println!("{}", format_stack(stack)),
+        (":quit", _) => return true,
+        _ => println!("unknown command `{}`, try :help", cmd)
+    }
+    false
+}
+
+/// Line editor support for the interactive REPL: completion of words and
+/// meta-commands, and highlighting of matching brackets.
+struct ReplHelper {
+    /// Names of the user definitions, refreshed before each line.
+    user_words: Vec<String>,
+    brackets: MatchingBracketHighlighter
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(|c: char| c.is_whitespace() || c == '[' || c == ']')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let prefix = &line[start..pos];
+        let mut candidates: Vec<String> = if start == 0 && prefix.starts_with(':') {
+            META_HELP.lines()
+                .filter_map(|l| l.split_whitespace().next())
+                .filter(|c| c.starts_with(prefix))
+                .map(|c| c.to_string())
+                .collect()
+        } else {
+            BUILTINS.iter()
+                .map(|w| w.to_string())
+                .chain(self.user_words.iter().cloned())
+                .filter(|w| w.starts_with(prefix))
+                .collect()
+        };
+        candidates.sort();
+        candidates.dedup();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        self.brackets.highlight(line, pos)
+    }
+
+    fn highlight_char(&self, line: &str, pos: usize, kind: CmdKind) -> bool {
+        self.brackets.highlight_char(line, pos, kind)
+    }
+}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".joy_history"))
+}
+
+/// Where REPL lines come from: a line editor on a terminal, or plain lines
+/// from the `Env` input when it's piped.
+enum Prompt {
+    Editor(Box<Editor<ReplHelper, DefaultHistory>>),
+    Plain
+}
+
+impl Prompt {
+    fn new() -> Prompt {
+        if !io::stdin().is_terminal() {
+            return Prompt::Plain;
+        }
+        match Editor::new() {
+            Ok(mut editor) => {
+                editor.set_helper(Some(ReplHelper {
+                    user_words: Vec::new(),
+                    brackets: MatchingBracketHighlighter::new()
+                }));
+                if let Some(path) = history_path() {
+                    let _ = editor.load_history(&path);
+                }
+                Prompt::Editor(Box::new(editor))
+            },
+            Err(_) => Prompt::Plain
+        }
+    }
+
+    /// Reads the next line, or `None` at the end of the input.
+    fn read_line(&mut self, env: &mut Env) -> Option<String> {
+        match self {
+            Prompt::Editor(editor) => {
+                if let Some(helper) = editor.helper_mut() {
+                    helper.user_words = env.programs.keys().filter(|n| !is_hidden(n)).cloned().collect();
+                }
+                match editor.readline("> ") {
+                    Ok(line) => {
+                        let _ = editor.add_history_entry(line.as_str());
+                        Some(line)
+                    },
+                    Err(ReadlineError::Interrupted) => Some(String::new()),
+                    Err(_) => None
+                }
+            },
+            Prompt::Plain => {
+                print!("> ");
+                io::stdout().flush().unwrap();
+                let mut input = String::new();
+                match env.input.read_line(&mut input) {
+                    Ok(0) => None,
+                    Ok(_) => Some(input),
+                    Err(error) => panic!("error: {}", error)
+                }
+            }
+        }
+    }
+
+    fn save_history(&mut self) {
+        if let (Prompt::Editor(editor), Some(path)) = (self, history_path()) {
+            let _ = editor.save_history(&path);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("check") {
+        let problems: usize = args[2..].iter().map(|path| check_file(path)).sum();
+        std::process::exit(if problems > 0 { 1 } else { 0 });
+    }
+    if args.get(1).map(String::as_str) == Some("fmt") {
+        let check = args[2..].iter().any(|a| a == "--check");
+        let files: Vec<String> = args[2..].iter().filter(|a| *a != "--check").cloned().collect();
+        std::process::exit(if fmt_files(&files, check) { 0 } else { 1 });
+    }
+    if args.get(1).map(String::as_str) == Some("lint") {
+        let mut allowed = Vec::new();
+        let mut files = Vec::new();
+        let mut rest = args[2..].iter();
+        while let Some(arg) = rest.next() {
+            if arg == "--allow" {
+                match rest.next().and_then(|id| lint_rule(id)) {
+                    Some(rule) => allowed.push(rule),
+                    None => {
+                        println!("--allow needs one of the rules {}", LINT_RULES.iter().map(|r| r.1).collect::<Vec<_>>().join(", "));
+                        std::process::exit(2);
+                    }
+                }
+            } else {
+                files.push(arg.clone());
+            }
+        }
+        let found: usize = files.iter().map(|path| lint_file(path, &allowed)).sum();
+        std::process::exit(if found > 0 { 1 } else { 0 });
+    }
+    if args.get(1).map(String::as_str) == Some("test") {
+        let mut paths: Vec<String> = args[2..].to_vec();
+        let junit = match paths.iter().position(|a| a == "--junit") {
+            Some(i) if i + 1 < paths.len() => paths.drain(i..i + 2).nth(1),
+            _ => None
+        };
+        let doc = paths.iter().any(|a| a == "--doc");
+        paths.retain(|a| a != "--doc");
+        let results = if doc {
+            run_doctests(&paths)
+        } else {
+            if paths.is_empty() {
+                paths.push(".".to_string());
+            }
+            run_tests(&paths)
+        };
+        std::process::exit(if report_tests(&results, junit.as_deref()) { 0 } else { 1 });
+    }
+    if args.get(1).map(String::as_str) == Some("doc") {
+        let mut files: Vec<String> = args[2..].to_vec();
+        let dir = match files.iter().position(|a| a == "-o") {
+            Some(i) if i + 1 < files.len() => files.drain(i..i + 2).nth(1).unwrap(),
+            _ => "doc".to_string()
+        };
+        std::process::exit(if doc_files(&files, &dir) { 0 } else { 1 });
+    }
+    println!("    a joy interpreter");
+    let mut stack = Vec::new();
+    let mut env = Env::new();
+    env.argv = args;
+    let prelude = !env.argv.iter().any(|a| a == "--no-prelude");
+    let rc = !env.argv.iter().any(|a| a == "--no-rc");
+    env.static_check = !env.argv.iter().any(|a| a == "--no-check");
+    env.debug = env.argv.iter().any(|a| a == "--debug");
+    env.argv.retain(|a| !matches!(a.as_str(), "--no-prelude" | "--no-rc" | "--no-check" | "--debug"));
+    let mut quit = prelude && load_source(PRELUDE, &mut stack, &mut env);
+    env.prelude = env.programs.clone();
+    if rc {
+        for path in rc_paths() {
+            if let Ok(source) = fs::read_to_string(&path) {
+                quit = quit || load_source(&source, &mut stack, &mut env);
+            }
+        }
+    }
+    let mut prompt = Prompt::new();
+
+    while !quit {
+        let input = match prompt.read_line(&mut env) {
+            Some(input) => input,
+            None => break
+        };
+
+        if input.trim_start().starts_with(':') {
+            quit = meta_command(&input, &mut stack, &mut env);
+            continue;
+        }
+        let mut comment = env.comment.clone();
+        let code = strip_comments(&input, &mut comment).0;
+        let quiet = code.trim().is_empty() || is_block_line(&code) || matches!(definition(code.trim()), Ok(("", _)));
+        quit = eval_line(&input, &mut stack, &mut env);
+        if !quiet {
+            println!("{}", format_stack(&stack));
+        }
+    }
+    prompt.save_history();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An interpreter with the prelude that reads and writes nothing.
+    fn env() -> Env {
+        let mut env = Env::with_io(Box::new(io::sink()), Box::new(io::empty()));
+        env.static_check = false;
+        load_source(PRELUDE, &mut Vec::new(), &mut env);
+        env
+    }
+
+    /// The stack `source` leaves, in Joy syntax.
+    fn run(source: &str) -> Result<String, JoyError> {
+        let mut env = env();
+        let mut stack = Vec::new();
+        run_line(source, &mut stack, &mut env)?;
+        Ok(joy_text(&stack))
+    }
+
+    fn parse(source: &str) -> Option<String> {
+        match expr(source) {
+            Ok(("", toks)) => Some(joy_text(&toks)),
+            _ => None
+        }
+    }
+
+    fn effect(source: &str) -> (Option<String>, Vec<String>) {
+        let env = env();
+        let (_, (name, _, body)) = definition(source).unwrap();
+        let (effect, errors) = infer_effect(&name, &body, &env);
+        (effect, errors.iter().map(|e| e.to_string()).collect())
+    }
+
+    #[test]
+    fn number_literals() {
+        assert_eq!(parse("1_000 -0x1F 0o17 0b1_01 0xff_ff"), Some("1000 -31 15 5 65535".to_string()));
+        assert_eq!(parse("1.5 -2.0e3"), Some("1.5 -2000.0".to_string()));
+    }
+
+    #[test]
+    fn separators_only_between_digits() {
+        assert_eq!(parse("1__0"), Some("1__0".to_string()));
+        assert_eq!(parse("0x1F_"), Some("0x1F_".to_string()));
+        assert_eq!(parse("1_"), Some("1_".to_string()));
+        assert!(matches!(token("_1"), Ok(("", Token::Symbol(_)))));
+        assert!(matches!(token("12abc"), Ok(("", Token::Symbol(_)))));
+    }
+
+    #[test]
+    fn chars_and_single_quoted_strings() {
+        assert!(matches!(token("'a"), Ok(("", Token::Char('a')))));
+        assert!(matches!(token("'\\n"), Ok(("", Token::Char('\n')))));
+        assert!(matches!(token("'a'"), Ok(("", Token::String(s))) if s == "a"));
+        assert!(matches!(token("'a b'"), Ok(("", Token::String(s))) if s == "a b"));
+        assert_eq!(parse("'a 'b"), Some("'a 'b".to_string()));
+        assert_eq!(parse("['x y']"), Some("[\"x y\"]".to_string()));
+    }
+
+    #[test]
+    fn unparsable_input_is_a_syntax_error() {
+        assert_eq!(run("1 ]").unwrap_err().kind, ErrorKind::Syntax);
+        assert_eq!(run("\"a\\qb\"").unwrap_err().kind, ErrorKind::Syntax);
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(run("1 1.0 = 1 2 < 'a 'b < \"b\" \"a\" > [1 2] [1 3] <").unwrap(), "true true true true true");
+        assert_eq!(run("-0.0 0.0 = -0.0 0.0 compare").unwrap(), "true -1");
+        assert_eq!(run("\"nan\" strtod dup = \"nan\" strtod dup !=").unwrap(), "false true");
+        assert_eq!(run("\"nan\" strtod 1.0 < \"nan\" strtod 1.0 >=").unwrap(), "false false");
+        assert_eq!(run("1 \"a\" compare \"a\" 1 compare").unwrap(), "-1 1");
+    }
+
+    #[test]
+    fn inferred_effects() {
+        assert_eq!(effect("sq == dup *"), (Some("(num -- num)".to_string()), Vec::new()));
+        assert_eq!(effect("two == 1 2"), (Some("(-- int int)".to_string()), Vec::new()));
+        assert_eq!(effect("swapped == swap"), (Some("(a b -- b a)".to_string()), Vec::new()));
+        assert_eq!(effect("len == size swap pop"), (Some("(list -- int)".to_string()), Vec::new()));
+        assert_eq!(effect("twice == [dup +] i"), (Some("(num -- num)".to_string()), Vec::new()));
+    }
+
+    #[test]
+    fn type_conflicts_are_reported() {
+        let (_, errors) = effect("bad == \"a\" 1 +");
+        assert_eq!(errors, vec!["type error: `+` needs num but gets string".to_string()]);
+        let (_, errors) = effect("worse == dup not swap 1 +");
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn declared_effects_are_checked() {
+        let mut env = env();
+        let mut stack = Vec::new();
+        run_line("sq (n -- n) == dup *", &mut stack, &mut env).unwrap();
+        assert!(check_definition("sq", &env).is_empty());
+        run_line("bad (n -- n n) == dup *", &mut stack, &mut env).unwrap();
+        assert_eq!(check_definition("bad", &env)[0].to_string(), "type error: declared (n -- n n) but inferred (num -- num)");
+    }
+
+    #[test]
+    fn injected_clock_and_seed() {
+        let mut env = env();
+        env.time = Box::new(|| 1_000_000);
+        env.clock = Box::new(|| 42);
+        env.seed = 7;
+        let mut stack = Vec::new();
+        run_line("time clock rand rand 7 srand rand", &mut stack, &mut env).unwrap();
+        let lcg = |seed: u64| seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        let first = (lcg(7) >> 33) as i64;
+        let second = (lcg(lcg(7)) >> 33) as i64;
+        assert_eq!(joy_text(&stack), format!("1000000 42 {} {} {}", first, second, first));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn top_level_underflow_is_reported() {
+        let env = env();
+        let mut checker = Checker::new(&env, true);
+        let mut shape = Shape::default();
+        checker.run(&expr("1 +").unwrap().1, &mut shape);
+        assert_eq!(checker.errors[0].kind, ErrorKind::Underflow);
+        assert_eq!(checker.errors[0].to_string(), "underflow error: `+` underflows the stack");
+    }
 }